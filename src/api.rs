@@ -1,9 +1,11 @@
 use log::{info, warn};
-use std::{collections::HashSet};
+use std::{cmp::Ordering, collections::{HashMap, HashSet}};
 
 use caramel::ns::api::{Client, ApiError};
 use caramel::ns::xml::{parse_wa_members, parse_world_regions};
 
+use crate::utils::unescape;
+
 pub async fn query_wa_nations(
     client: &Client, set: &mut HashSet<String>
 ) -> Result<(), ApiError> {
@@ -25,6 +27,181 @@ pub async fn query_wa_nations(
     return Ok(());
 }
 
+pub async fn query_nation_flag(
+    client: &Client, nation: &str
+) -> Result<Option<String>, ApiError> {
+    let response = client.make_request_with_retry(vec![
+        ("nation", nation), ("q", "flag")
+    ]).await?;
+
+    let flag = response.split_once("<FLAG>")
+        .and_then(|(_, rest)| rest.split_once("</FLAG>"))
+        .map(|(flag, _)| flag.trim().to_string());
+
+    if flag.is_none() {
+        warn!("Invalid XML from flag API request for {nation}");
+    }
+
+    return Ok(flag);
+}
+
+pub async fn query_region_nations(
+    client: &Client, region: &str
+) -> Result<Option<Vec<String>>, ApiError> {
+    let response = client.make_request_with_retry(vec![
+        ("region", region), ("q", "nations")
+    ]).await?;
+
+    let nations = response.split_once("<NATIONS>")
+        .and_then(|(_, rest)| rest.split_once("</NATIONS>"))
+        .map(|(nations, _)| nations.split(':').filter(|n| !n.is_empty()).map(str::to_string).collect());
+
+    if nations.is_none() {
+        warn!("Invalid XML from nations API request for {region}");
+    }
+
+    return Ok(nations);
+}
+
+pub async fn query_region_delegate(
+    client: &Client, region: &str
+) -> Result<Option<Option<String>>, ApiError> {
+    let response = client.make_request_with_retry(vec![
+        ("region", region), ("q", "delegate")
+    ]).await?;
+
+    // Regions without a delegate report "0"
+    let delegate = response.split_once("<DELEGATE>")
+        .and_then(|(_, rest)| rest.split_once("</DELEGATE>"))
+        .map(|(delegate, _)| Some(delegate.trim().to_string()).filter(|d| !d.is_empty() && d != "0"));
+
+    if delegate.is_none() {
+        warn!("Invalid XML from delegate API request for {region}");
+    }
+
+    return Ok(delegate);
+}
+
+pub async fn query_nation_endorsements(
+    client: &Client, nation: &str
+) -> Result<Option<Vec<String>>, ApiError> {
+    let response = client.make_request_with_retry(vec![
+        ("nation", nation), ("q", "endorsements")
+    ]).await?;
+
+    let endorsements = response.split_once("<ENDORSEMENTS>")
+        .and_then(|(_, rest)| rest.split_once("</ENDORSEMENTS>"))
+        .map(|(endorsements, _)| endorsements.split(',').filter(|n| !n.is_empty()).map(str::to_string).collect());
+
+    if endorsements.is_none() {
+        warn!("Invalid XML from endorsements API request for {nation}");
+    }
+
+    return Ok(endorsements);
+}
+
+/// Vote totals for the resolution at vote in one chamber, and how each delegate voted (true for FOR).
+#[derive(Debug, Clone)]
+pub struct WaVotes {
+    pub name: String,
+    pub votes_for: u64,
+    pub votes_against: u64,
+    pub delegates: HashMap<String, bool>,
+}
+
+impl WaVotes {
+    /// Which side is ahead, or None on a tie.
+    pub fn lead(&self) -> Option<Ordering> {
+        Some(self.votes_for.cmp(&self.votes_against)).filter(|lead| lead.is_ne())
+    }
+}
+
+fn tag_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    xml.split_once(&format!("<{tag}>"))
+        .and_then(|(_, rest)| rest.split_once(&format!("</{tag}>")))
+        .map(|(text, _)| text.trim())
+}
+
+fn delegate_names(xml: &str) -> Vec<String> {
+    xml.split("<NATION>").skip(1)
+        .filter_map(|rest| rest.split_once("</NATION>"))
+        .map(|(nation, _)| nation.trim().to_string())
+        .collect()
+}
+
+fn parse_wa_votes(response: &str) -> Option<WaVotes> {
+    // There's no <RESOLUTION> content when nothing is at vote
+    let resolution = tag_text(response, "RESOLUTION")?;
+
+    let mut delegates = HashMap::new();
+    delegates.extend(delegate_names(tag_text(resolution, "DELVOTES_FOR")?).into_iter().map(|nation| (nation, true)));
+    delegates.extend(delegate_names(tag_text(resolution, "DELVOTES_AGAINST")?).into_iter().map(|nation| (nation, false)));
+
+    Some(WaVotes {
+        name: unescape(tag_text(resolution, "NAME")?),
+        votes_for: tag_text(resolution, "TOTAL_VOTES_FOR")?.parse().ok()?,
+        votes_against: tag_text(resolution, "TOTAL_VOTES_AGAINST")?.parse().ok()?,
+        delegates,
+    })
+}
+
+pub async fn query_wa_votes(
+    client: &Client, council: &str
+) -> Result<Option<WaVotes>, ApiError> {
+    let response = client.make_request_with_retry(vec![
+        ("wa", council), ("q", "resolution+delvotes")
+    ]).await?;
+
+    let votes = parse_wa_votes(&response);
+
+    if votes.is_none() {
+        warn!("No resolution at vote in WA API request for council {council}");
+    }
+
+    return Ok(votes);
+}
+
+/// What a Security Council proposal targets, e.g. `category` "Liberation" with `option` "R:testregionia".
+#[derive(Debug, Clone)]
+pub struct ScTarget {
+    pub category: String,
+    pub option: String,
+}
+
+fn parse_sc_target(proposal: &str, name: &str) -> Option<ScTarget> {
+    if unescape(tag_text(proposal, "NAME")?) != name { return None; }
+
+    Some(ScTarget {
+        category: unescape(tag_text(proposal, "CATEGORY")?),
+        option: unescape(tag_text(proposal, "OPTION")?),
+    })
+}
+
+fn find_sc_target(response: &str, name: &str, at_vote: bool) -> Option<ScTarget> {
+    if at_vote {
+        tag_text(response, "RESOLUTION").and_then(|resolution| parse_sc_target(resolution, name))
+    } else {
+        response.split("<PROPOSAL ").skip(1).find_map(|proposal| parse_sc_target(proposal, name))
+    }
+}
+
+/// Looks up the target of a Security Council proposal by name, either among submitted proposals or the resolution at vote.
+pub async fn query_sc_target(
+    client: &Client, name: &str, at_vote: bool
+) -> Result<Option<ScTarget>, ApiError> {
+    let response = client.make_request_with_retry(vec![
+        ("wa", "2"), ("q", if at_vote { "resolution" } else { "proposals" })
+    ]).await?;
+
+    let target = find_sc_target(&response, name, at_vote);
+
+    if target.is_none() {
+        warn!("Couldn't find SC proposal {name} in WA API request");
+    }
+
+    return Ok(target);
+}
+
 pub async fn query_regions_by_tag(
     client: &Client, set: &mut HashSet<String>, params: Vec<String>
 ) -> Result<(), ApiError> {
@@ -44,4 +221,94 @@ pub async fn query_regions_by_tag(
     }
 
     return Ok(());
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOTES: &str = "<WA council=\"1\"><RESOLUTION>
+        <NAME>Repeal &quot;Example&quot;</NAME>
+        <DELVOTES_FOR><DELEGATE><NATION>testlandia</NATION><VOTES>20</VOTES></DELEGATE>
+        <DELEGATE><NATION> maxtopia </NATION><VOTES>5</VOTES></DELEGATE></DELVOTES_FOR>
+        <DELVOTES_AGAINST></DELVOTES_AGAINST>
+        <TOTAL_VOTES_FOR>1234</TOTAL_VOTES_FOR>
+        <TOTAL_VOTES_AGAINST>567</TOTAL_VOTES_AGAINST>
+    </RESOLUTION></WA>";
+
+    #[test]
+    fn tag_text_is_trimmed() {
+        assert_eq!(tag_text(VOTES, "TOTAL_VOTES_FOR"), Some("1234"));
+        assert_eq!(tag_text(VOTES, "DELVOTES_AGAINST"), Some(""));
+        assert_eq!(tag_text(VOTES, "MISSING"), None);
+    }
+
+    #[test]
+    fn tag_text_finds_nested_tags() {
+        let resolution = tag_text(VOTES, "RESOLUTION").unwrap();
+        assert!(tag_text(resolution, "NAME").is_some());
+        assert!(tag_text("<WA council=\"1\"><RESOLUTION></RESOLUTION></WA>", "RESOLUTION").unwrap().is_empty());
+    }
+
+    #[test]
+    fn delegate_names_are_listed() {
+        assert_eq!(delegate_names(tag_text(VOTES, "DELVOTES_FOR").unwrap()), vec!["testlandia", "maxtopia"]);
+        assert!(delegate_names(tag_text(VOTES, "DELVOTES_AGAINST").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn wa_votes_are_parsed() {
+        let votes = parse_wa_votes(VOTES).unwrap();
+        assert_eq!(votes.name, "Repeal \"Example\"");
+        assert_eq!((votes.votes_for, votes.votes_against), (1234, 567));
+        assert_eq!(votes.delegates, HashMap::from([("testlandia".to_string(), true), ("maxtopia".to_string(), true)]));
+
+        assert!(parse_wa_votes("<WA council=\"1\"><RESOLUTION></RESOLUTION></WA>").is_none());
+    }
+
+    #[test]
+    fn leads_ignore_ties() {
+        let votes = |votes_for, votes_against| WaVotes { name: String::new(), votes_for, votes_against, delegates: HashMap::new() };
+
+        assert_eq!(votes(3, 2).lead(), Some(Ordering::Greater));
+        assert_eq!(votes(2, 3).lead(), Some(Ordering::Less));
+        assert_eq!(votes(2, 2).lead(), None);
+    }
+
+    const PROPOSALS: &str = "<WA council=\"2\"><PROPOSALS>
+        <PROPOSAL id=\"testlandia_1\"><CATEGORY>Commendation</CATEGORY><NAME>Commend Maxtopia</NAME>
+        <OPTION>N:maxtopia</OPTION><PROPOSED_BY>testlandia</PROPOSED_BY></PROPOSAL>
+        <PROPOSAL id=\"testlandia_2\"><CATEGORY>Liberation</CATEGORY><NAME>Liberate B &amp; C</NAME>
+        <OPTION>R:b_&amp;_c</OPTION><PROPOSED_BY>testlandia</PROPOSED_BY></PROPOSAL>
+    </PROPOSALS></WA>";
+
+    const RESOLUTION: &str = "<WA council=\"2\"><RESOLUTION>
+        <CATEGORY>Condemnation</CATEGORY><NAME>Condemn &quot;Testlandia&quot;</NAME><OPTION>N:testlandia</OPTION>
+        <TOTAL_VOTES_FOR>10</TOTAL_VOTES_FOR><TOTAL_VOTES_AGAINST>5</TOTAL_VOTES_AGAINST>
+    </RESOLUTION></WA>";
+
+    #[test]
+    fn submitted_sc_targets_are_found_by_name() {
+        let target = find_sc_target(PROPOSALS, "Commend Maxtopia", false).unwrap();
+        assert_eq!(target.category, "Commendation");
+        assert_eq!(target.option, "N:maxtopia");
+
+        assert!(find_sc_target(PROPOSALS, "Commend Testlandia", false).is_none());
+    }
+
+    #[test]
+    fn sc_target_names_are_unescaped() {
+        let target = find_sc_target(PROPOSALS, "Liberate B & C", false).unwrap();
+        assert_eq!(target.option, "R:b_&_c");
+
+        let target = find_sc_target(RESOLUTION, "Condemn \"Testlandia\"", true).unwrap();
+        assert_eq!(target.option, "N:testlandia");
+    }
+
+    #[test]
+    fn sc_targets_at_vote_are_only_looked_up_in_the_resolution() {
+        assert!(find_sc_target(RESOLUTION, "Commend Maxtopia", true).is_none());
+        assert!(find_sc_target(PROPOSALS, "Commend Maxtopia", true).is_none());
+        assert!(find_sc_target("<WA council=\"2\"><RESOLUTION></RESOLUTION></WA>", "Commend Maxtopia", true).is_none());
+    }
+}
@@ -1,33 +1,422 @@
-use std::{collections::{HashMap, HashSet}, sync::Arc, time::Instant};
+use std::{cmp::Ordering, collections::{HashMap, HashSet, VecDeque}, sync::Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::{RwLock, mpsc::Sender};
-use log::error;
+use log::{error, info, warn};
+use serenity::all::MessageId;
 
-use crate::{config::Config, worker::NSQuery};
+use caramel::types::akari::Event;
+
+use crate::{api::WaVotes, config::{Config, OutputConfig, OutputStyle}, webhook::WebhookToken, worker::NSQuery};
+
+/// A Discord message sent for an RMB post.
+#[derive(Debug, Clone)]
+pub struct TrackedMessage {
+    pub hook: WebhookToken,
+    pub message: MessageId,
+}
+
+/// A recent RMB post, kept to show it as context for replies.
+#[derive(Debug, Clone, Default)]
+pub struct CachedPost {
+    pub nation: String,
+    pub content: String,
+}
+
+/// What Bubble knows about a nation from the daily dump and the region APIs, kept up to date from the feed.
+/// `arrived` and `previous_regions` are only known for moves seen since Bubble started, and only
+/// cover regions Bubble watches (directly or through a tag).
+#[derive(Debug, Clone, Default)]
+pub struct NationInfo {
+    pub region: String,
+    pub endorsements: HashSet<String>,
+    pub endorsing: HashSet<String>,
+    pub influence: String,
+    pub arrived: Option<u64>,
+    pub previous_regions: HashSet<String>,
+}
+
+/// A resolution at vote whose votes are being tracked, from when it reaches the floor until it passes or fails.
+/// `votes` is the last poll, starting with no votes when the resolution reaches the floor,
+/// and `lead` is the last side ahead, ignoring ties.
+#[derive(Debug, Clone)]
+pub struct VoteTally {
+    pub event: Event,
+    pub votes: Option<WaVotes>,
+    pub lead: Option<Ordering>,
+}
+
+/// An RMB post waiting to be sent as part of a digest.
+#[derive(Debug, Clone)]
+pub struct DigestPost {
+    pub postid: String,
+    pub nation: String,
+    pub excerpt: String,
+}
+
+/// The RMB posts of a region waiting to be sent together to one webhook.
+/// Only the first `MAX_DIGEST_POSTS` posts are kept, the rest are only counted in `postids` and `authors`.
+#[derive(Debug, Clone)]
+pub struct Digest {
+    pub region: String,
+    pub output_config: OutputConfig,
+    pub posts: Vec<DigestPost>,
+    pub postids: HashSet<String>,
+    pub authors: HashSet<String>,
+    pub time: u64,
+    pub due: Instant,
+}
+
+/// Digests are kept per region and webhook, and separately for outputs sharing a webhook with a different
+/// interval or style, so each is sent the way its own config asks for.
+pub type DigestKey = (String, String, Duration, OutputStyle);
+
+/// A map that only keeps the most recently added keys, dropping the oldest ones once full.
+pub struct RecentMap<V> {
+    values: HashMap<String, V>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl<V> RecentMap<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { values: HashMap::new(), order: VecDeque::new(), capacity }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.values.get(key)
+    }
+
+    /// Returns the value for a key, adding a default value if it's missing.
+    pub fn entry(&mut self, key: &str) -> &mut V where V: Default {
+        if !self.values.contains_key(key) {
+            self.order.push_back(key.to_string());
+
+            if self.order.len() > self.capacity && let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+
+        self.values.entry(key.to_string()).or_default()
+    }
+
+    pub fn insert(&mut self, key: &str, value: V) where V: Default {
+        *self.entry(key) = value;
+    }
+}
+
+const MAX_TRACKED_POSTS: usize = 10000;
+const MAX_CACHED_POSTS: usize = 2000;
+const MAX_CACHED_FLAGS: usize = 5000;
+const MAX_DIGEST_POSTS: usize = 100;
 
 pub struct NSCache {
     pub wa_nations: RwLock<HashSet<String>>,
+    pub nations: RwLock<HashMap<String, NationInfo>>,
     pub tag_cloud: RwLock<HashMap<String, HashSet<String>>>,
     pub next_tag_query: RwLock<(Instant, usize)>,
+    pub flags: RwLock<RecentMap<String>>,
+    pub pending_flags: RwLock<HashSet<String>>,
+    pub rmb_messages: RwLock<RecentMap<Vec<TrackedMessage>>>,
+    pub rmb_posts: RwLock<RecentMap<CachedPost>>,
+    pub digests: RwLock<HashMap<DigestKey, Digest>>,
+    pub delegates: RwLock<HashMap<String, String>>,
+    pub delegate_peaks: RwLock<HashMap<String, usize>>,
+    pub delegate_levels: RwLock<HashMap<(String, String, usize), usize>>,
+    pub vote_tallies: RwLock<HashMap<String, VoteTally>>,
 }
 
 const TAG_UPDATE_MIN_REGIONS: usize = 10;
 const TAG_UPDATE_COOLDOWN: u64 = 60 * 30; // 30 minutes
+const MAX_SNAPSHOT_AGE: u64 = 60 * 60 * 24; // 1 day
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+fn string_set(value: Option<&toml::Value>) -> HashSet<String> {
+    match value {
+        Some(toml::Value::Array(a)) => a.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => HashSet::new()
+    }
+}
+
+fn string_array(set: &HashSet<String>) -> toml::Value {
+    toml::Value::Array(set.iter().cloned().map(toml::Value::String).collect())
+}
 
 impl NSCache {
     pub fn new() -> Arc<Self> {
         Arc::new(
             Self {
                 wa_nations: RwLock::new(HashSet::new()),
+                nations: RwLock::new(HashMap::new()),
                 tag_cloud: RwLock::new(HashMap::new()),
-                next_tag_query: RwLock::new((Instant::now(), 0))
+                next_tag_query: RwLock::new((Instant::now(), 0)),
+                flags: RwLock::new(RecentMap::new(MAX_CACHED_FLAGS)),
+                pending_flags: RwLock::new(HashSet::new()),
+                rmb_messages: RwLock::new(RecentMap::new(MAX_TRACKED_POSTS)),
+                rmb_posts: RwLock::new(RecentMap::new(MAX_CACHED_POSTS)),
+                digests: RwLock::new(HashMap::new()),
+                delegates: RwLock::new(HashMap::new()),
+                delegate_peaks: RwLock::new(HashMap::new()),
+                delegate_levels: RwLock::new(HashMap::new()),
+                vote_tallies: RwLock::new(HashMap::new()),
             }
         )
     }
 
+    /// Records the residents of a region from the API, keeping what's already known about them.
+    /// Nations recorded in the region that aren't residents anymore left while Bubble wasn't watching,
+    /// so their region, arrival time and influence there are cleared.
+    pub async fn set_residents(&self, region: &str, residents: Vec<String>) {
+        let mut nations = self.nations.write().await;
+        let current: HashSet<&String> = residents.iter().collect();
+
+        for (nation, info) in nations.iter_mut() {
+            if info.region == region && !current.contains(nation) {
+                info.region.clear();
+                info.arrived = None;
+                info.influence.clear();
+            }
+        }
+
+        for nation in residents {
+            nations.entry(nation).or_default().region = region.to_string();
+        }
+    }
+
+    /// Removes every endorsement given or received by a nation, e.g. when it leaves the WA or its region.
+    pub async fn clear_endorsements(&self, nation: &str) {
+        let mut nations = self.nations.write().await;
+
+        let Some(info) = nations.get_mut(nation) else { return };
+        let endorsers = std::mem::take(&mut info.endorsements);
+        let endorsing = std::mem::take(&mut info.endorsing);
+
+        for endorser in endorsers {
+            if let Some(info) = nations.get_mut(&endorser) {
+                info.endorsing.remove(nation);
+            }
+        }
+
+        for endorsed in endorsing {
+            if let Some(info) = nations.get_mut(&endorsed) {
+                info.endorsements.remove(nation);
+            }
+        }
+    }
+
+    /// Records an endorsement (or its withdrawal), returning how many endorsements the endorsed nation has now.
+    /// Only endorsements of nations Bubble already knows about are kept, so the cache doesn't grow with every
+    /// endorsement in the world. Returns 0 for other nations.
+    pub async fn set_endorsement(&self, endorser: &str, endorsed: &str, endorsing: bool) -> usize {
+        let mut nations = self.nations.write().await;
+        if !nations.contains_key(endorsed) { return 0; }
+
+        let info = nations.entry(endorser.to_string()).or_default();
+        if endorsing {
+            info.endorsing.insert(endorsed.to_string());
+        } else {
+            info.endorsing.remove(endorsed);
+        }
+
+        let info = nations.entry(endorsed.to_string()).or_default();
+        if endorsing {
+            info.endorsements.insert(endorser.to_string());
+        } else {
+            info.endorsements.remove(endorser);
+        }
+
+        info.endorsements.len()
+    }
+
+    /// Replaces a nation's endorsements with a fresh list from the API.
+    pub async fn set_endorsements(&self, nation: &str, endorsers: Vec<String>) {
+        let mut nations = self.nations.write().await;
+
+        let previous = std::mem::take(&mut nations.entry(nation.to_string()).or_default().endorsements);
+        for endorser in previous {
+            if let Some(info) = nations.get_mut(&endorser) {
+                info.endorsing.remove(nation);
+            }
+        }
+
+        for endorser in &endorsers {
+            nations.entry(endorser.clone()).or_default().endorsing.insert(nation.to_string());
+        }
+
+        if let Some(info) = nations.get_mut(nation) {
+            info.endorsements = endorsers.into_iter().collect();
+        }
+    }
+
+    /// Records a region's new delegate (or lack of one), resetting its endorsement peak.
+    pub async fn set_delegate(&self, region: &str, delegate: Option<String>) {
+        let mut delegates = self.delegates.write().await;
+        let mut peaks = self.delegate_peaks.write().await;
+        self.delegate_levels.write().await.retain(|(levels_region, _, _), _| levels_region != region);
+
+        match delegate {
+            Some(delegate) => {
+                let count = self.nations.read().await.get(&delegate).map_or(0, |info| info.endorsements.len());
+                peaks.insert(region.to_string(), count);
+                delegates.insert(region.to_string(), delegate);
+            },
+            None => {
+                peaks.remove(region);
+                delegates.remove(region);
+            }
+        }
+    }
+
+    /// Updates the highest endorsement count of a region's delegate, returning the peak.
+    pub async fn update_delegate_peak(&self, region: &str, count: usize) -> usize {
+        let mut peaks = self.delegate_peaks.write().await;
+        let peak = peaks.entry(region.to_string()).or_insert(count);
+        *peak = (*peak).max(count);
+        *peak
+    }
+
+    /// Whether a delegate's endorsements have dropped another `threshold` below its peak since the last alert
+    /// sent to a webhook. Swinging back and forth around a level doesn't alert again, only getting back to the peak does.
+    pub async fn reached_endorsement_level(&self, region: &str, hook_url: &str, threshold: usize, drop: usize) -> bool {
+        let mut levels = self.delegate_levels.write().await;
+        let key = (region.to_string(), hook_url.to_string(), threshold);
+
+        if drop == 0 {
+            levels.remove(&key);
+            return false;
+        }
+
+        let level = drop / threshold;
+        let alerted = levels.entry(key).or_insert(0);
+        if level <= *alerted { return false; }
+
+        *alerted = level;
+        true
+    }
+
+    /// Adds a post to the digest for its region and webhook, starting a new digest if there isn't one yet.
+    /// A post already in the digest (e.g. sent to the same webhook by both a region and a tag) is only added once.
+    pub async fn add_to_digest(&self, region: &str, output_config: &OutputConfig, time: u64, post: DigestPost) {
+        let Some(interval) = output_config.digest else { return };
+        let mut digests = self.digests.write().await;
+
+        let key = (region.to_string(), output_config.hook_url.clone(), interval, output_config.style);
+        let digest = digests.entry(key).or_insert_with(|| Digest {
+            region: region.to_string(),
+            output_config: output_config.clone(),
+            posts: Vec::new(),
+            postids: HashSet::new(),
+            authors: HashSet::new(),
+            time,
+            due: Instant::now() + interval,
+        });
+
+        if !digest.postids.insert(post.postid.clone()) { return; }
+        digest.authors.insert(post.nation.clone());
+
+        if digest.posts.len() < MAX_DIGEST_POSTS {
+            digest.posts.push(post);
+        }
+    }
+
+    /// Removes and returns the digests that are due to be sent.
+    pub async fn take_due_digests(&self) -> Vec<Digest> {
+        let now = Instant::now();
+        let mut digests = self.digests.write().await;
+
+        let due: Vec<DigestKey> = digests.iter()
+            .filter(|(_, digest)| digest.due <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        due.iter().filter_map(|key| digests.remove(key)).collect()
+    }
+
+    /// Removes and returns every pending digest, due or not, e.g. to send them before shutting down.
+    pub async fn take_all_digests(&self) -> Vec<Digest> {
+        self.digests.write().await.drain().map(|(_, digest)| digest).collect()
+    }
+
+    /// Saves the WA nations, tag cloud and tag query state, so they're available right away after a restart.
+    pub async fn save_snapshot(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut table = toml::Table::new();
+        let (last_query, regions) = *self.next_tag_query.read().await;
+
+        table.insert("saved_at".into(), toml::Value::Integer(unix_time() as i64));
+        table.insert("last_tag_query".into(), toml::Value::Integer(unix_time().saturating_sub(last_query.elapsed().as_secs()) as i64));
+        table.insert("new_regions".into(), toml::Value::Integer(regions as i64));
+        table.insert("wa_nations".into(), string_array(&*self.wa_nations.read().await));
+        table.insert("tag_cloud".into(), toml::Value::Table(
+            self.tag_cloud.read().await.iter().map(|(tag, regions)| (tag.clone(), string_array(regions))).collect()
+        ));
+
+        // Write to a temporary file first so a crash while saving doesn't leave a broken snapshot
+        let temp_path = format!("{path}.tmp");
+        tokio::fs::write(&temp_path, toml::to_string(&table)?).await?;
+        tokio::fs::rename(&temp_path, path).await?;
+
+        Ok(())
+    }
+
+    /// Loads a snapshot saved by `save_snapshot`, returning whether it was recent enough to be used.
+    /// Snapshots older than a day are ignored. WA queries still run as usual after loading, the snapshot only
+    /// fills the gap until they finish, but tags are only queried again once the saved tag query is due.
+    /// Tags that aren't in `config` anymore are left out.
+    pub async fn load_snapshot(&self, path: &str, config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+        let table: toml::Table = toml::from_str(&tokio::fs::read_to_string(path).await?)?;
+
+        let Some(saved_at) = table.get("saved_at").and_then(|v| v.as_integer()) else {
+            return Err("Snapshot is missing 'saved_at'".into());
+        };
+
+        let age = unix_time().saturating_sub(saved_at as u64);
+
+        if age > MAX_SNAPSHOT_AGE {
+            warn!("Ignoring cache snapshot from {} hours ago", age / 3600);
+            return Ok(false);
+        }
+
+        let wa_nations = string_set(table.get("wa_nations"));
+        info!("Loaded {} WA nations from cache snapshot ({} minutes old)", wa_nations.len(), age / 60);
+        *self.wa_nations.write().await = wa_nations;
+
+        // Tags removed from the config since the snapshot was saved aren't tracked anymore
+        if let Some(toml::Value::Table(t)) = table.get("tag_cloud") {
+            *self.tag_cloud.write().await = t.iter()
+                .filter(|(tag, _)| config.tags.contains_key(*tag))
+                .map(|(tag, regions)| (tag.clone(), string_set(Some(regions))))
+                .collect();
+        }
+
+        let last_query = table.get("last_tag_query").and_then(|v| v.as_integer()).unwrap_or(0) as u64;
+        let regions = table.get("new_regions").and_then(|v| v.as_integer()).unwrap_or(0) as usize;
+
+        let since_query = Duration::from_secs(unix_time().saturating_sub(last_query));
+        *self.next_tag_query.write().await = (
+            Instant::now().checked_sub(since_query).unwrap_or_else(Instant::now), regions
+        );
+
+        Ok(true)
+    }
+
     pub async fn tick_tag_query(&self) {
         self.next_tag_query.write().await.1 += 1;
     }
 
+    /// Whether the tag query should run at startup: when there's no usable snapshot, a configured tag
+    /// is missing from it, or the query it saved is due.
+    pub async fn should_run_startup_tag_query(&self, config: &Config, loaded_snapshot: bool) -> bool {
+        if !loaded_snapshot { return true; }
+
+        let tag_cloud = self.tag_cloud.read().await;
+        if config.tags.keys().any(|tag| !tag_cloud.contains_key(tag)) { return true; }
+        drop(tag_cloud);
+
+        self.should_run_tag_query().await
+    }
+
     pub async fn should_run_tag_query(&self) -> bool {
         let query = self.next_tag_query.read().await;
 
@@ -46,4 +435,145 @@ impl NSCache {
             });
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config_str;
+
+    fn test_config() -> Config {
+        parse_config_str(r#"
+            [input]
+            exchange_name = "akari_events"
+
+            [tag.feeder]
+            join = {}
+        "#).unwrap()
+    }
+
+    fn snapshot_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("bubble-{name}-{}.toml", std::process::id())).to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn snapshots_round_trip() {
+        let path = snapshot_path("snapshot");
+        let cache = NSCache::new();
+
+        *cache.wa_nations.write().await = HashSet::from(["testlandia".to_string(), "maxtopia".to_string()]);
+        cache.tag_cloud.write().await.insert("feeder".to_string(), HashSet::from(["the_north_pacific".to_string()]));
+        cache.next_tag_query.write().await.1 = 7;
+        cache.save_snapshot(&path).await.unwrap();
+
+        let loaded = NSCache::new();
+        assert!(loaded.load_snapshot(&path, &test_config()).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(*loaded.wa_nations.read().await, *cache.wa_nations.read().await);
+        assert_eq!(*loaded.tag_cloud.read().await, *cache.tag_cloud.read().await);
+        assert_eq!(loaded.next_tag_query.read().await.1, 7);
+    }
+
+    #[tokio::test]
+    async fn snapshot_tags_removed_from_the_config_are_dropped() {
+        let path = snapshot_path("removed-tag-snapshot");
+        let cache = NSCache::new();
+
+        cache.tag_cloud.write().await.insert("feeder".to_string(), HashSet::from(["the_north_pacific".to_string()]));
+        cache.tag_cloud.write().await.insert("removed".to_string(), HashSet::from(["lazarus".to_string()]));
+        cache.save_snapshot(&path).await.unwrap();
+
+        let loaded = NSCache::new();
+        loaded.load_snapshot(&path, &test_config()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.tag_cloud.read().await.contains_key("feeder"));
+        assert!(!loaded.tag_cloud.read().await.contains_key("removed"));
+    }
+
+    #[tokio::test]
+    async fn saved_tag_queries_stay_due() {
+        let path = snapshot_path("tag-query-snapshot");
+        let now = unix_time();
+        let snapshot = |last_query: u64| format!("saved_at = {now}\nlast_tag_query = {last_query}\nnew_regions = 20\n");
+
+        std::fs::write(&path, snapshot(now - TAG_UPDATE_COOLDOWN - 60)).unwrap();
+        let cache = NSCache::new();
+        cache.load_snapshot(&path, &test_config()).await.unwrap();
+        assert!(cache.should_run_tag_query().await);
+
+        std::fs::write(&path, snapshot(now)).unwrap();
+        let cache = NSCache::new();
+        cache.load_snapshot(&path, &test_config()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!cache.should_run_tag_query().await);
+    }
+
+    #[tokio::test]
+    async fn old_snapshots_are_ignored() {
+        let path = snapshot_path("old-snapshot");
+        let saved_at = unix_time() - MAX_SNAPSHOT_AGE - 60;
+        std::fs::write(&path, format!("saved_at = {saved_at}\nwa_nations = [\"testlandia\"]\n")).unwrap();
+
+        let cache = NSCache::new();
+        assert!(!cache.load_snapshot(&path, &test_config()).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(cache.wa_nations.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshots_without_a_save_time_are_rejected() {
+        let path = snapshot_path("broken-snapshot");
+        std::fs::write(&path, "wa_nations = [\"testlandia\"]\n").unwrap();
+
+        let result = NSCache::new().load_snapshot(&path, &test_config()).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn endorsement_levels_are_sent_once() {
+        let cache = NSCache::new();
+        let reached = async |drop| cache.reached_endorsement_level("testregionia", "hook", 5, drop).await;
+
+        assert!(!reached(4).await);
+        assert!(reached(5).await);
+        assert!(!reached(4).await);
+        assert!(!reached(5).await);
+        assert!(reached(12).await);
+        assert!(!reached(0).await);
+        assert!(reached(5).await);
+    }
+
+    #[tokio::test]
+    async fn residents_that_left_are_cleared() {
+        let cache = NSCache::new();
+        cache.set_residents("testregionia", vec!["testlandia".to_string(), "maxtopia".to_string()]).await;
+        cache.nations.write().await.get_mut("maxtopia").unwrap().arrived = Some(100);
+        cache.set_residents("elsewhere", vec!["elsewhereland".to_string()]).await;
+        cache.set_residents("testregionia", vec!["testlandia".to_string()]).await;
+
+        let nations = cache.nations.read().await;
+        assert_eq!(nations["testlandia"].region, "testregionia");
+        assert_eq!(nations["maxtopia"].region, "");
+        assert_eq!(nations["maxtopia"].arrived, None);
+        assert_eq!(nations["elsewhereland"].region, "elsewhere");
+    }
+
+    #[tokio::test]
+    async fn endorsements_of_unknown_nations_are_not_kept() {
+        let cache = NSCache::new();
+        cache.set_residents("testregionia", vec!["testlandia".to_string()]).await;
+
+        assert_eq!(cache.set_endorsement("maxtopia", "testlandia", true).await, 1);
+        assert_eq!(cache.set_endorsement("testlandia", "elsewhere", true).await, 0);
+
+        let nations = cache.nations.read().await;
+        assert_eq!(nations.len(), 2);
+        assert!(nations["maxtopia"].endorsing.contains("testlandia"));
+        assert!(nations["testlandia"].endorsing.is_empty());
+    }
+}
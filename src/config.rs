@@ -1,19 +1,81 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
 use std::fs;
 use std::process::exit;
 use log::{error, warn};
 use toml::Table;
 use hex_color::HexColor;
+use regex::{Regex, RegexBuilder};
 
 use caramel::webhook::{Webhook, parse_webhook_from_url};
 
 use crate::cache::NSCache;
+use crate::webhook::{WebhookToken, parse_webhook_token};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputStyle {
+    Embed,
+    Compact,
+}
+
+impl OutputStyle {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "embed" => Some(Self::Embed),
+            "compact" => Some(Self::Compact),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteMode {
+    Defang,
+    Strip,
+}
+
+impl InviteMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "defang" => Some(Self::Defang),
+            "strip" => Some(Self::Strip),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ButtonConfig {
+    pub label: String,
+    pub url: String,
+}
+
+/// An alert sent when an RMB post matches a word or pattern.
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    pub pattern: Regex,
+    pub label: String,
+    pub color: Option<HexColor>,
+    pub hook: Option<String>,
+    pub mentions: Vec<String>,
+}
 
 #[derive(Debug, Clone)]
 pub struct OutputConfig {
     pub color: Option<HexColor>,
     pub hook: Webhook,
+    pub hook_token: Option<WebhookToken>,
+    pub hook_url: String,
     pub mentions: Vec<u64>,
+    pub style: OutputStyle,
+    pub timestamp: Option<String>,
+    pub buttons: Vec<ButtonConfig>,
+    pub default_buttons: bool,
+    pub invites: InviteMode,
+    pub flag: bool,
+    pub reply_to_watched: Vec<String>,
+    pub alert: Option<String>,
+    pub digest: Option<Duration>,
+    pub threshold: Option<usize>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,12 +83,25 @@ pub struct EventConfig {
     pub color: Option<HexColor>,
     pub hook: Option<String>,
     pub mentions: Vec<String>,
+    pub style: Option<OutputStyle>,
+    pub timestamp: Option<String>,
+    pub buttons: Vec<ButtonConfig>,
+    pub default_buttons: bool,
+    pub invites: InviteMode,
+    pub flag: bool,
+    pub reply_to_watched: Vec<String>,
+    pub alerts: Vec<AlertConfig>,
+    pub nations: Vec<String>,
+    pub digest: Option<Duration>,
+    pub threshold: Option<usize>,
+    pub interval: Option<Duration>,
 }
 
 #[derive(Debug)]
 pub struct RegionConfig {
     pub default_hook: Option<String>,
     pub default_color: Option<HexColor>,
+    pub default_style: Option<OutputStyle>,
     pub exclude: Vec<String>,
     pub events: HashMap<String, EventConfig>,
 }
@@ -36,10 +111,35 @@ pub struct InputConfig {
     pub exchange_name: String,
 }
 
+#[derive(Debug)]
+pub struct CacheConfig {
+    pub snapshot: Option<String>,
+    pub snapshot_interval: Duration,
+    pub nations_dump: Option<String>,
+}
+
+/// A webhook from the `webhooks` section, with its default style and the token and URL that identify its messages.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub webhook: Webhook,
+    pub style: Option<OutputStyle>,
+    pub token: Option<WebhookToken>,
+    pub url: String,
+}
+
+/// A region, tag or world config a happening is sent through.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConfigTarget {
+    Region(String),
+    Tag(String),
+    World,
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub input: InputConfig,
-    pub webhooks: HashMap<String, Webhook>,
+    pub cache: CacheConfig,
+    pub webhooks: HashMap<String, WebhookConfig>,
     pub roles: HashMap<String, u64>,
     pub regions: HashMap<String, RegionConfig>,
     pub tags: HashMap<String, RegionConfig>,
@@ -48,23 +148,37 @@ pub struct Config {
 
 impl Config {
     fn get_event_impl(&self, region_config: &RegionConfig, event: &str) -> Option<OutputConfig> {
-        let Some(event_config) = region_config.events.get(event) else { return None };
+        // `embassy` only covers the specific embassy happenings a region doesn't configure itself
+        let event_config = region_config.events.get(event).or_else(|| {
+            event.starts_with("embassy-").then(|| region_config.events.get("embassy")).flatten()
+        })?;
 
-        let mut webhook: Option<Webhook> = None; 
+        let hook_name = event_config.hook.as_ref().or(region_config.default_hook.as_ref())?;
+        self.build_output(region_config, event_config, hook_name)
+    }
 
-        if let Some(hook) = &event_config.hook {
-            webhook = self.webhooks.get(hook).cloned();
-        } else if let Some(hook) = &region_config.default_hook {
-            webhook = self.webhooks.get(hook).cloned();
-        }
+    fn build_output(
+        &self, region_config: &RegionConfig, event_config: &EventConfig, hook_name: &String
+    ) -> Option<OutputConfig> {
+        let webhook = self.webhooks.get(hook_name)?;
 
         let mut result = OutputConfig { 
-            color: None, hook: match webhook {
-                Some(v) => v,
-                None => return None
-            }, mentions: Vec::new()
+            color: None, hook: webhook.webhook.clone(), hook_token: webhook.token.clone(),
+            hook_url: webhook.url.clone(), mentions: Vec::new(), style: OutputStyle::Embed, timestamp: event_config.timestamp.clone(),
+            buttons: event_config.buttons.clone(), default_buttons: event_config.default_buttons,
+            invites: event_config.invites, flag: event_config.flag,
+            reply_to_watched: event_config.reply_to_watched.clone(), alert: None,
+            digest: event_config.digest, threshold: event_config.threshold
          };
 
+        if let Some(style) = event_config.style {
+            result.style = style;
+        } else if let Some(style) = region_config.default_style {
+            result.style = style;
+        } else if let Some(style) = webhook.style {
+            result.style = style;
+        }
+
         if let Some(color) = &event_config.color {
             result.color = Some(*color);
         } else if let Some(color) = &region_config.default_color {
@@ -80,37 +194,137 @@ impl Config {
         Some(result)
     }
 
+    /// Resolves the alerts of a region's `rmb` happening that match a post's text.
+    /// Alerts use their own hook, color and role pings, falling back to the happening's.
+    fn get_alerts_impl(&self, region_config: &RegionConfig, text: &str) -> Vec<OutputConfig> {
+        let Some(event_config) = region_config.events.get("rmb") else { return Vec::new() };
+
+        event_config.alerts.iter().filter(|alert| alert.pattern.is_match(text)).filter_map(|alert| {
+            let hook_name = alert.hook.as_ref()
+                .or(event_config.hook.as_ref())
+                .or(region_config.default_hook.as_ref())?;
+
+            let mut result = self.build_output(region_config, event_config, hook_name)?;
+            result.color = alert.color.or(result.color);
+            result.mentions = alert.mentions.iter().filter_map(|mention| self.roles.get(mention).copied()).collect();
+            result.reply_to_watched.clear();
+            result.alert = Some(alert.label.clone());
+            result.digest = None;
+
+            Some(result)
+        }).collect()
+    }
+
+    pub async fn get_rmb_alerts(&self, cache: Arc<NSCache>, region: &str, text: &str) -> Vec<OutputConfig> {
+        let mut result = Vec::new();
+
+        if let Some(region_config) = self.regions.get(region) {
+            result.extend(self.get_alerts_impl(region_config, text));
+        }
+
+        for (tag, regions) in cache.tag_cloud.read().await.iter() {
+            let Some(config) = self.tags.get(tag) else { continue };
+            if !regions.contains(region) || config.exclude.contains(&region.to_string()) { continue; }
+            result.extend(self.get_alerts_impl(config, text));
+        }
+
+        if let Some(world) = &self.world {
+            result.extend(self.get_alerts_impl(world, text));
+        }
+
+        result
+    }
+
     pub fn get_region_event(&self, region: &str, event: &str) -> Option<OutputConfig> {
         let Some(region_config) = self.regions.get(region) else { return None };
 
         return self.get_event_impl(region_config, event);
     }
 
+    pub fn get_tag_event(&self, tag: &str, event: &str) -> Option<OutputConfig> {
+        self.get_event_impl(self.tags.get(tag)?, event)
+    }
+
+    pub fn get_target_event(&self, target: &ConfigTarget, event: &str) -> Option<OutputConfig> {
+        match target {
+            ConfigTarget::Region(region) => self.get_region_event(region, event),
+            ConfigTarget::Tag(tag) => self.get_tag_event(tag, event),
+            ConfigTarget::World => self.get_world_event(event),
+        }
+    }
+
     pub fn get_world_event(&self, event: &str) -> Option<OutputConfig> {
         return self.get_event_impl(self.world.as_ref()?, event);
     }
 
-    pub async fn get_tag_events(&self, cache: Arc<NSCache>, region: &str, event: &str) -> Vec<OutputConfig> {
-        cache.tag_cloud.read().await.iter().filter_map(|(tag, regions)| {
-            if regions.contains(region) { Some(tag.clone()) } else { None }
-        }).filter_map(|tag| {
-            let config = self.tags.get(&tag).unwrap();
-            if config.exclude.contains(&region.to_string()) { return None; }
-            self.get_event_impl(config, event)
+    /// Whether a happening is configured for any region or tag.
+    pub fn wants_event(&self, event: &str) -> bool {
+        self.regions.values().chain(self.tags.values()).any(|region_config| region_config.events.contains_key(event))
+    }
+
+    /// How often to poll vote totals for resolutions at vote, or None if no vote tally happening is configured.
+    pub fn tally_interval(&self) -> Option<Duration> {
+        let world = self.world.as_ref()?;
+        let events: Vec<&EventConfig> = TALLY_CATEGORIES.iter().filter_map(|category| world.events.get(*category)).collect();
+        if events.is_empty() { return None; }
+
+        Some(events.iter().filter_map(|event| event.interval).min().unwrap_or(DEFAULT_TALLY_INTERVAL))
+    }
+
+    /// Delegates whose votes are watched by the `wa-vote`, `ga-vote` and `sc-vote` happenings.
+    pub fn watched_delegates(&self) -> HashSet<String> {
+        let Some(world) = &self.world else { return HashSet::new() };
+
+        ["wa-vote", "ga-vote", "sc-vote"].iter()
+            .filter_map(|category| world.events.get(*category))
+            .flat_map(|event| event.nations.iter().cloned())
+            .collect()
+    }
+
+    /// Resolves the tags containing any of the given regions, sending each tag's happening once.
+    pub async fn get_tag_events(&self, cache: Arc<NSCache>, regions: &[&String], event: &str) -> Vec<OutputConfig> {
+        cache.tag_cloud.read().await.iter().filter_map(|(tag, tagged)| {
+            let config = self.tags.get(tag)?;
+            let included = regions.iter().any(|region| tagged.contains(*region) && !config.exclude.contains(region));
+            if included { self.get_event_impl(config, event) } else { None }
         }).collect()
     }
 }
 
-fn parse_webhook_map(table: &Table) -> HashMap<String, Webhook> {
+fn parse_style(value: &toml::Value, key: &str) -> Option<OutputStyle> {
+    if let toml::Value::String(s) = value {
+        let style = OutputStyle::parse(s);
+        if style.is_none() {
+            warn!("Unknown style '{s}' for '{key}', expected 'embed' or 'compact'");
+        }
+        style
+    } else {
+        warn!("Config key {key} should have a string value");
+        None
+    }
+}
+
+fn parse_webhook_map(table: &Table) -> HashMap<String, WebhookConfig> {
     let mut result = HashMap::new();
 
     for (key, value) in table {
-        if let toml::Value::String(url) = value {
-            if let Some(webhook) = parse_webhook_from_url(url) {
-                result.insert(key.clone(), webhook);
-            } else {
-                warn!("Couldn't parse webhook '{key}'");
-            }
+        let (url, style) = match value {
+            toml::Value::String(url) => (url, None),
+            toml::Value::Table(t) => {
+                let Some(toml::Value::String(url)) = t.get("url") else {
+                    warn!("Webhook '{key}' is missing a 'url' value");
+                    continue;
+                };
+
+                (url, t.get("style").and_then(|v| parse_style(v, key)))
+            },
+            _ => continue
+        };
+
+        if let Some(webhook) = parse_webhook_from_url(url) {
+            result.insert(key.clone(), WebhookConfig { webhook, style, token: parse_webhook_token(url), url: url.clone() });
+        } else {
+            warn!("Couldn't parse webhook '{key}'");
         }
     }
 
@@ -131,9 +345,76 @@ fn parse_role_map(table: &Table) -> HashMap<String, u64> {
     result
 }
 
+/// The styles Discord accepts in `<t:timestamp:style>` markup.
+const TIMESTAMP_STYLES: &[&str] = &["t", "T", "d", "D", "f", "F", "R"];
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 5);
+const DEFAULT_DIGEST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_TALLY_INTERVAL: Duration = Duration::from_secs(60 * 15);
+
+const TALLY_CATEGORIES: [&str; 9] = [
+    "wa-tally", "ga-tally", "sc-tally", "wa-lead", "ga-lead", "sc-lead", "wa-vote", "ga-vote", "sc-vote"
+];
+
+/// Parses a duration such as "30m" or "1h".
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit())?);
+    let number: u64 = number.parse().ok()?;
+
+    let seconds = match unit.trim() {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        "d" => number * 60 * 60 * 24,
+        _ => return None
+    };
+
+    if seconds == 0 { None } else { Some(Duration::from_secs(seconds)) }
+}
+
+/// Parses an alert, matching either a whole `word` or a regex `pattern`, ignoring case.
+fn parse_alert(table: &Table, key: &str) -> Option<AlertConfig> {
+    let (source, label) = match (table.get("word"), table.get("pattern")) {
+        (Some(toml::Value::String(word)), _) => (format!(r"\b{}\b", regex::escape(word)), word.clone()),
+        (_, Some(toml::Value::String(pattern))) => (pattern.clone(), pattern.clone()),
+        _ => {
+            warn!("Alert for '{key}' should have a 'word' or 'pattern' string value");
+            return None;
+        }
+    };
+
+    let pattern = match RegexBuilder::new(&source).case_insensitive(true).build() {
+        Ok(pattern) => pattern,
+        Err(err) => {
+            warn!("Invalid alert pattern '{label}' for '{key}': {err}");
+            return None;
+        }
+    };
+
+    let mut alert = AlertConfig { pattern, label, color: None, hook: None, mentions: Vec::new() };
+
+    if let Some(toml::Value::String(s)) = table.get("color") {
+        alert.color = Some(HexColor::parse_rgb(s).expect("Not a valid color string"));
+    }
+
+    if let Some(toml::Value::String(s)) = table.get("hook") {
+        alert.hook = Some(s.clone());
+    }
+
+    if let Some(toml::Value::Array(a)) = table.get("mentions") {
+        for mention in a {
+            if let toml::Value::String(s) = mention {
+                alert.mentions.push(s.clone());
+            }
+        }
+    }
+
+    Some(alert)
+}
+
 fn parse_region(table: &Table) -> RegionConfig {
     let mut result = RegionConfig { 
-        default_hook: None, default_color: None, exclude: Vec::new(), events: HashMap::new() 
+        default_hook: None, default_color: None, default_style: None, exclude: Vec::new(), events: HashMap::new() 
     };
 
     for (key, value) in table {
@@ -145,6 +426,8 @@ fn parse_region(table: &Table) -> RegionConfig {
             if let toml::Value::String(v) = value {
                 result.default_color = Some(HexColor::parse_rgb(v).expect("Not a valid color string"));
             }
+        } else if key == "default-style" {
+            result.default_style = parse_style(value, key);
         } else if key == "exclude" {
             if let toml::Value::Array(a) = value {
                 for region in a {
@@ -159,7 +442,12 @@ fn parse_region(table: &Table) -> RegionConfig {
                 continue;
             }
 
-            let mut event: EventConfig = EventConfig { color: None, hook: None, mentions: Vec::new() };
+            let mut event: EventConfig = EventConfig { 
+                color: None, hook: None, mentions: Vec::new(), style: None, timestamp: None,
+                buttons: Vec::new(), default_buttons: true, invites: InviteMode::Defang, flag: false,
+                reply_to_watched: Vec::new(), alerts: Vec::new(), nations: Vec::new(),
+                digest: None, threshold: None, interval: None
+            };
 
             if let Some(toml::Value::String(s)) = t.get("color") {
                 event.color = Some(HexColor::parse_rgb(s).expect("Not a valid color string"));
@@ -177,6 +465,91 @@ fn parse_region(table: &Table) -> RegionConfig {
                 }
             }
 
+            if let Some(v) = t.get("style") {
+                event.style = parse_style(v, key);
+            }
+
+            if let Some(toml::Value::String(s)) = t.get("timestamp") {
+                if TIMESTAMP_STYLES.contains(&s.as_str()) {
+                    event.timestamp = Some(s.clone());
+                } else {
+                    warn!("Unknown timestamp style '{s}' for '{key}', expected one of t, T, d, D, f, F or R");
+                }
+            }
+
+            if let Some(toml::Value::Array(a)) = t.get("buttons") {
+                for button in a {
+                    if let toml::Value::Table(b) = button
+                    && let Some(toml::Value::String(label)) = b.get("label")
+                    && let Some(toml::Value::String(url)) = b.get("url") {
+                        event.buttons.push(ButtonConfig { label: label.clone(), url: url.clone() });
+                    } else {
+                        warn!("Button for '{key}' should have a 'label' and 'url' string value");
+                    }
+                }
+            }
+
+            if let Some(toml::Value::Boolean(b)) = t.get("default-buttons") {
+                event.default_buttons = *b;
+            }
+
+            if let Some(toml::Value::String(s)) = t.get("invites") {
+                match InviteMode::parse(s) {
+                    Some(mode) => event.invites = mode,
+                    None => warn!("Unknown invite mode '{s}' for '{key}', expected 'defang' or 'strip'")
+                }
+            }
+
+            if let Some(toml::Value::Boolean(b)) = t.get("flag") {
+                event.flag = *b;
+            }
+
+            if let Some(toml::Value::Array(a)) = t.get("reply-to-watched") {
+                for nation in a {
+                    if let toml::Value::String(s) = nation {
+                        event.reply_to_watched.push(s.to_lowercase().replace(' ', "_"));
+                    }
+                }
+            }
+
+            if let Some(toml::Value::Array(a)) = t.get("nations") {
+                for nation in a {
+                    if let toml::Value::String(s) = nation {
+                        event.nations.push(s.to_lowercase().replace(' ', "_"));
+                    }
+                }
+            }
+
+            if let Some(toml::Value::String(s)) = t.get("interval") {
+                match parse_duration(s) {
+                    Some(interval) => event.interval = Some(interval),
+                    None => warn!("Invalid interval '{s}' for '{key}', expected a number followed by s, m, h or d")
+                }
+            }
+
+            if let Some(toml::Value::String(mode)) = t.get("mode") {
+                match mode.as_str() {
+                    "digest" => event.digest = Some(event.interval.unwrap_or(DEFAULT_DIGEST_INTERVAL)),
+                    "instant" => {},
+                    _ => warn!("Unknown mode '{mode}' for '{key}', expected 'instant' or 'digest'")
+                }
+            }
+
+            if let Some(v) = t.get("threshold") {
+                match v.as_integer() {
+                    Some(n) if n > 0 => event.threshold = Some(n as usize),
+                    _ => warn!("Threshold for '{key}' should be a positive integer")
+                }
+            }
+
+            if let Some(toml::Value::Array(a)) = t.get("alerts") {
+                for alert in a {
+                    if let toml::Value::Table(alert) = alert {
+                        event.alerts.extend(parse_alert(alert, key));
+                    }
+                }
+            }
+
             result.events.insert(key.clone(), event);
         }
     }
@@ -197,8 +570,11 @@ fn parse_regions(table: &Table) -> HashMap<String, RegionConfig> {
 }
 
 pub fn parse_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
-    let contents = fs::read_to_string(path)?;
-    let table: toml::Table = toml::from_str(&contents.as_str())?;
+    parse_config_str(&fs::read_to_string(path)?)
+}
+
+pub(crate) fn parse_config_str(contents: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let table: toml::Table = toml::from_str(contents)?;
 
     let input: InputConfig = if let Some(toml::Value::Table(t)) = table.get("input") {
         let exchange_name = if let Some(toml::Value::String(s)) = t.get("exchange_name") { s.clone() } else {
@@ -212,6 +588,28 @@ pub fn parse_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
         exit(1);
     };
 
+    let mut cache = CacheConfig { 
+        snapshot: None, snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        nations_dump: None
+    };
+
+    if let Some(toml::Value::Table(t)) = table.get("cache") {
+        if let Some(toml::Value::String(s)) = t.get("snapshot") {
+            cache.snapshot = Some(s.clone());
+        }
+
+        if let Some(toml::Value::String(s)) = t.get("nations-dump") {
+            cache.nations_dump = Some(s.clone());
+        }
+
+        if let Some(toml::Value::String(s)) = t.get("snapshot-interval") {
+            match parse_duration(s) {
+                Some(interval) => cache.snapshot_interval = interval,
+                None => warn!("Invalid snapshot interval '{s}', expected a number followed by s, m, h or d")
+            }
+        }
+    }
+
     let webhooks = if let Some(toml::Value::Table(t)) = table.get("webhooks") {
         parse_webhook_map(t)
     } else {
@@ -255,5 +653,104 @@ pub fn parse_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
         }
     };
 
-    Ok(Config { input, webhooks, roles, regions, tags, world })
-}
\ No newline at end of file
+    Ok(Config { input, cache, webhooks, roles, regions, tags, world })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nscode, render::plain_text};
+
+    fn alert(source: &str) -> Option<AlertConfig> {
+        parse_alert(&source.parse::<Table>().unwrap(), "rmb")
+    }
+
+    #[test]
+    fn word_alerts_match_whole_words_ignoring_case() {
+        let alert = alert(r#"word = "raid""#).unwrap();
+
+        assert_eq!(alert.label, "raid");
+        assert!(alert.pattern.is_match("Planning a RAID tonight"));
+        assert!(!alert.pattern.is_match("raiders and a braid"));
+    }
+
+    #[test]
+    fn alert_settings_are_parsed() {
+        let alert = alert(r##"pattern = "tag(ged|ging)?"
+hook = "security"
+color = "#ff0000"
+mentions = ["security"]"##).unwrap();
+
+        assert_eq!(alert.label, "tag(ged|ging)?");
+        assert_eq!(alert.hook.as_deref(), Some("security"));
+        assert_eq!(alert.mentions, vec!["security".to_string()]);
+        assert!(alert.color.is_some());
+    }
+
+    #[test]
+    fn alerts_without_a_word_or_pattern_are_skipped() {
+        assert!(alert(r#"hook = "security""#).is_none());
+    }
+
+    #[test]
+    fn alerts_ignore_link_targets_and_quotes() {
+        let alert = alert(r#"word = "raid""#).unwrap();
+        let text = |content: &str| plain_text(&nscode::parse(content));
+
+        assert!(!alert.pattern.is_match(&text("[url=https://raid.example]a link[/url]")));
+        assert!(!alert.pattern.is_match(&text("[quote=testlandia;1]raid[/quote]no")));
+        assert!(alert.pattern.is_match(&text("[b]raid[/b] at update")));
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration(" 2 h "), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        for value in ["", "h", "15", "0m", "-5m", "1.5h", "10x", "1 day"] {
+            assert_eq!(parse_duration(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn embassy_is_a_fallback_for_specific_embassy_happenings() {
+        let config = parse_config_str(r#"
+            [input]
+            exchange_name = "akari_events"
+
+            [webhooks]
+            general = "https://discord.com/api/webhooks/123456789012345678/general-token"
+            embassies = "https://discord.com/api/webhooks/223456789012345678/embassies-token"
+
+            [region.testregionia]
+            embassy = { hook = "general" }
+            embassy-open = { hook = "embassies" }
+        "#).unwrap();
+        let hook = |event: &str| config.get_region_event("testregionia", event).map(|output| output.hook_url);
+
+        assert_eq!(hook("embassy-open").as_deref(), Some("https://discord.com/api/webhooks/223456789012345678/embassies-token"));
+        assert_eq!(hook("embassy-close").as_deref(), Some("https://discord.com/api/webhooks/123456789012345678/general-token"));
+        assert_eq!(hook("wfe"), None);
+    }
+
+    #[test]
+    fn unknown_timestamp_styles_are_ignored() {
+        let config = parse_config_str(r#"
+            [input]
+            exchange_name = "akari_events"
+
+            [region.testregionia]
+            join = { timestamp = "R" }
+            leave = { timestamp = "X" }
+        "#).unwrap();
+        let events = &config.regions["testregionia"].events;
+
+        assert_eq!(events["join"].timestamp.as_deref(), Some("R"));
+        assert_eq!(events["leave"].timestamp, None);
+    }
+}
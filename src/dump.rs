@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+use std::{fs::File, io::{self, BufRead, BufReader}};
+
+use flate2::read::GzDecoder;
+
+use crate::{cache::NationInfo, utils::unescape};
+
+/// A nation from the daily dump, with the fields Bubble keeps track of.
+#[derive(Debug, Default)]
+pub struct DumpNation {
+    pub name: String,
+    pub region: String,
+    pub wa: bool,
+    pub endorsements: Vec<String>,
+    pub influence: String,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase().replace(' ', "_")
+}
+
+/// Reads a `nations.xml` dump one element at a time, calling `on_nation` for each nation,
+/// so the whole dump never has to be in memory at once.
+pub fn read_nations_dump(mut reader: impl BufRead, mut on_nation: impl FnMut(DumpNation)) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut nation = DumpNation::default();
+
+    loop {
+        buffer.clear();
+        if reader.read_until(b'<', &mut buffer)? == 0 { break; }
+        if buffer.last() == Some(&b'<') { buffer.pop(); }
+
+        // Only the text of direct children of <NATION> is needed
+        if path.len() == 3 {
+            text.push_str(&String::from_utf8_lossy(&buffer));
+        }
+
+        buffer.clear();
+        if reader.read_until(b'>', &mut buffer)? == 0 { break; }
+        if buffer.last() == Some(&b'>') { buffer.pop(); }
+
+        let tag = String::from_utf8_lossy(&buffer);
+
+        if tag.starts_with('?') || tag.starts_with('!') || tag.ends_with('/') {
+            continue;
+        }
+
+        if let Some(name) = tag.strip_prefix('/') {
+            if path.len() == 3 && path[1] == "NATION" {
+                let value = unescape(text.trim());
+
+                match name {
+                    "NAME" => nation.name = normalize(&value),
+                    "REGION" => nation.region = normalize(&value),
+                    "UNSTATUS" => nation.wa = value != "Non-member",
+                    "ENDORSEMENTS" => nation.endorsements = value.split(',')
+                        .filter(|endorser| !endorser.is_empty())
+                        .map(normalize)
+                        .collect(),
+                    "INFLUENCE" => nation.influence = value,
+                    _ => {}
+                }
+            } else if path.len() == 2 && name == "NATION" {
+                on_nation(std::mem::take(&mut nation));
+            }
+
+            path.pop();
+        } else {
+            path.push(tag.split_whitespace().next().unwrap_or("").to_string());
+            text.clear();
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the WA nations and per-nation info from a gzipped `nations.xml.gz` dump.
+/// Endorsements are indexed both ways, so each nation also knows who it endorses.
+pub fn load_nations_dump(path: &str) -> io::Result<(HashSet<String>, HashMap<String, NationInfo>)> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+
+    let mut wa_nations = HashSet::new();
+    let mut nations = HashMap::new();
+
+    read_nations_dump(reader, |nation| {
+        if nation.wa {
+            wa_nations.insert(nation.name.clone());
+        }
+
+        nations.insert(nation.name, NationInfo {
+            region: nation.region,
+            endorsements: nation.endorsements.into_iter().collect(),
+            influence: nation.influence,
+            ..Default::default()
+        });
+    })?;
+
+    let endorsements: Vec<(String, String)> = nations.iter()
+        .flat_map(|(nation, info)| info.endorsements.iter().map(move |endorser| (endorser.clone(), nation.clone())))
+        .collect();
+
+    for (endorser, endorsed) in endorsements {
+        nations.entry(endorser).or_default().endorsing.insert(endorsed);
+    }
+
+    Ok((wa_nations, nations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/nations.xml.gz");
+
+    #[test]
+    fn fixture_dump_is_loaded() {
+        let (wa_nations, nations) = load_nations_dump(FIXTURE).unwrap();
+
+        assert_eq!(nations.len(), 3);
+        assert_eq!(wa_nations, HashSet::from(["testlandia".to_string(), "maxtopia".to_string()]));
+
+        let testlandia = &nations["testlandia"];
+        assert_eq!(testlandia.region, "testregionia");
+        assert_eq!(testlandia.influence, "Eminence Grise");
+        assert_eq!(testlandia.endorsements, HashSet::from(["maxtopia".to_string()]));
+        assert_eq!(nations["maxtopia"].endorsing, HashSet::from(["testlandia".to_string()]));
+
+        assert_eq!(nations["b_&_c_republic"].region, "the_south_pacific");
+        assert!(nations["b_&_c_republic"].endorsements.is_empty());
+    }
+
+    #[test]
+    fn nested_elements_are_not_read_as_fields() {
+        let xml = "<NATIONS><NATION><NAME>Testlandia</NAME><GOVT><NAME>Ignored</NAME></GOVT>\
+            <REGION>Testregionia</REGION><UNSTATUS>Non-member</UNSTATUS></NATION></NATIONS>";
+
+        let mut result = Vec::new();
+        read_nations_dump(xml.as_bytes(), |nation| result.push(nation)).unwrap();
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].name, "testlandia");
+        assert_eq!(result[0].region, "testregionia");
+        assert!(!result[0].wa);
+    }
+}
@@ -1,84 +1,582 @@
-use std::sync::Arc;
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, sync::Arc};
 
-use crate::cache::NSCache;
+use itertools::Itertools;
+
+use crate::api::{ScTarget, WaVotes};
+use crate::cache::{CachedPost, NSCache, NationInfo, VoteTally};
+use crate::{config::{Config, ConfigTarget, RegionConfig}, nscode::{self, Tag}};
 use caramel::types::akari::Event;
 
+#[derive(Default)]
 pub struct EventData {
     pub name: &'static str,
     pub nation: Option<String>,
     pub region: Option<String>,
+    pub other_region: Option<String>,
+    pub residency: Option<Residency>,
+    pub endorsements: Option<EndorsementCount>,
+    pub wa: bool,
+}
+
+/// A delegate's endorsement count after an endorsement happening, and its highest count since taking office.
+#[derive(Debug, Clone, Copy)]
+pub struct EndorsementCount {
+    pub count: usize,
+    pub peak: usize,
+}
+
+/// How long a nation had lived in the region it's leaving, or whether it lived in the region it's moving to before.
+#[derive(Debug, Clone, Default)]
+pub struct Residency {
+    pub since: Option<u64>,
+    pub returning: bool,
+    pub influence: Option<String>,
+}
+
+/// The `ga-` or `sc-` version of a WA category, for the chamber named in the happening.
+fn council_category(name: &str, chamber: Option<&String>) -> Option<&'static str> {
+    let general_assembly = match chamber?.as_str() {
+        "General Assembly" => true,
+        "Security Council" => false,
+        _ => return None
+    };
+
+    Some(match (name, general_assembly) {
+        ("wa-floor", true) => "ga-floor",
+        ("wa-floor", false) => "sc-floor",
+        ("wa-submit", true) => "ga-submit",
+        ("wa-submit", false) => "sc-submit",
+        ("wa-pass", true) => "ga-pass",
+        ("wa-pass", false) => "sc-pass",
+        ("wa-fail", true) => "ga-fail",
+        ("wa-fail", false) => "sc-fail",
+        ("wa-discard", true) => "ga-discard",
+        ("wa-discard", false) => "sc-discard",
+        ("wa-approve", true) => "ga-approve",
+        ("wa-approve", false) => "sc-approve",
+        ("wa-tally", true) => "ga-tally",
+        ("wa-tally", false) => "sc-tally",
+        ("wa-lead", true) => "ga-lead",
+        ("wa-lead", false) => "sc-lead",
+        ("wa-vote", true) => "ga-vote",
+        ("wa-vote", false) => "sc-vote",
+        _ => return None
+    })
+}
+
+/// Whether moves into and out of a region are recorded, i.e. it's configured directly or through a tag.
+/// Other moves aren't, so following every move in the world doesn't grow the nation cache.
+async fn tracks_residency(region: &str, config: &Config, cache: &NSCache) -> bool {
+    config.regions.contains_key(region) || cache.tag_cloud.read().await.values().any(|regions| regions.contains(region))
+}
+
+/// Records a nation moving (or being ejected) from one region to another, clearing its endorsements.
+/// Returns its residency in the region it left and in the one it joined.
+async fn record_move(
+    nation: &str, origin: &str, destination: &str, time: u64, config: &Config, cache: &NSCache
+) -> (Residency, Residency) {
+    let tracks_origin = tracks_residency(origin, config, cache).await;
+    let tracks_destination = tracks_residency(destination, config, cache).await;
+    let mut nations = cache.nations.write().await;
+
+    // Nations Bubble doesn't know about yet are only added when they move into a tracked region
+    if !tracks_destination && !nations.contains_key(nation) {
+        return (Residency::default(), Residency::default());
+    }
+
+    let info = nations.entry(nation.to_string()).or_default();
+
+    let leaving = Residency { 
+        since: info.arrived, returning: false, 
+        influence: Some(info.influence.clone()).filter(|influence| !influence.is_empty()) 
+    };
+    let joining = Residency { since: None, returning: info.previous_regions.contains(destination), influence: None };
+
+    if tracks_origin {
+        info.previous_regions.insert(origin.to_string());
+    }
+
+    // Influence is reset when a nation relocates
+    if info.region != destination {
+        info.influence.clear();
+    }
+
+    info.region = destination.to_string();
+    info.arrived = Some(time);
+    drop(nations);
+
+    // Endorsements are lost when moving
+    cache.clear_endorsements(nation).await;
+
+    (leaving, joining)
 }
 
-pub async fn classify_event(event: &Event, cache: Arc<NSCache>) -> Option<Vec<EventData>> {
+pub async fn classify_event(event: &Event, config: &Config, cache: Arc<NSCache>) -> Option<Vec<EventData>> {
     match event.category.as_str() {
         "ncte" => {
             let mut wa_nations = cache.wa_nations.write().await;
             let nation = event.receptor.as_ref()?;
             let is_wa = wa_nations.remove(nation);
-            Some(vec![EventData { name: if is_wa { "wacte" } else { "cte" }, nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()) }])
+            cache.clear_endorsements(nation).await;
+            cache.nations.write().await.remove(nation);
+            Some(vec![EventData { name: if is_wa { "wacte" } else { "cte" }, nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()), ..Default::default() }])
         },
         "wadmit" => {
             let nation = event.actor.as_ref()?;
             cache.wa_nations.write().await.insert(nation.clone());
-            Some(vec![EventData { name: "admit", nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()) }])
+            Some(vec![EventData { name: "admit", nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()), ..Default::default() }])
         },
         "wresign" => {
             let nation = event.actor.as_ref()?;
             cache.wa_nations.write().await.remove(nation);
-            Some(vec![EventData { name: "resign", nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()) }])
+            cache.clear_endorsements(nation).await;
+            Some(vec![EventData { name: "resign", nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()), ..Default::default() }])
         },
         "wkick" => {
             let nation = event.actor.as_ref()?;
             cache.wa_nations.write().await.remove(nation);
-            Some(vec![EventData { name: "kick", nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()) }])
+            cache.clear_endorsements(nation).await;
+            Some(vec![EventData { name: "wakick", nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()), ..Default::default() }])
+        },
+        "endo" | "unendo" => {
+            let endorser = event.actor.as_ref()?;
+            let endorsed = event.receptor.as_ref()?;
+            let name = if event.category == "endo" { "endo" } else { "unendo" };
+
+            let count = cache.set_endorsement(endorser, endorsed, name == "endo").await;
+
+            let region = match &event.origin {
+                Some(origin) => origin.clone(),
+                None => match cache.nations.read().await.get(endorsed) {
+                    Some(info) if !info.region.is_empty() => info.region.clone(),
+                    _ => return Some(vec![])
+                }
+            };
+
+            let mut result = vec![EventData { name, nation: Some(endorser.clone()), region: Some(region.clone()), ..Default::default() }];
+
+            if cache.delegates.read().await.get(&region) == Some(endorsed) {
+                let peak = cache.update_delegate_peak(&region, count).await;
+                result.push(EventData { 
+                    name: "delegate-endo-change", nation: Some(endorser.clone()), region: Some(region),
+                    endorsements: Some(EndorsementCount { count, peak }), ..Default::default()
+                });
+            }
+
+            Some(result)
         },
         "move" => {
             let nation = event.actor.as_ref()?;
+            let origin = event.origin.as_ref()?;
+            let destination = event.destination.as_ref()?;
             let is_wa = cache.wa_nations.read().await.contains(nation);
+            let (leaving, joining) = record_move(nation, origin, destination, event.time, config, &cache).await;
+
             Some(vec![
-                EventData { name: if is_wa { "wajoin" } else { "join" }, nation: Some(nation.clone()), region: Some(event.destination.as_ref()?.clone()) },
-                EventData { name: if is_wa { "waleave" } else { "leave" }, nation: Some(nation.clone()), region: Some(event.origin.as_ref()?.clone()) }
+                EventData { name: if is_wa { "wajoin" } else { "join" }, nation: Some(nation.clone()), region: Some(destination.clone()), residency: Some(joining), ..Default::default() },
+                EventData { name: if is_wa { "waleave" } else { "leave" }, nation: Some(nation.clone()), region: Some(origin.clone()), residency: Some(leaving), ..Default::default() }
             ])
         },
-        "rmbpost" => Some(vec![EventData { 
-            name: "rmb", 
-            nation: Some(event.actor.as_ref()?.clone()), 
-            region: Some(event.origin.as_ref()?.clone()) 
+        "rmbpost" => {
+            let nation = event.actor.as_ref()?;
+
+            if let (Some(postid), Some(content)) = (event.data.first(), event.data.get(1)) {
+                cache.rmb_posts.write().await.insert(postid, CachedPost { nation: nation.clone(), content: content.clone() });
+            }
+
+            Some(vec![EventData { 
+                name: "rmb", 
+                nation: Some(nation.clone()), 
+                region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+            }])
+        },
+        "rmbsuppress" | "rmbdelete" => Some(vec![EventData { 
+            name: "rmb-suppress", 
+            nation: event.actor.clone(), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
         }]),
         "rupdate" => Some(vec![EventData { 
             name: "update", nation: None,
-            region: Some(event.origin.as_ref()?.clone()) 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
         }]),
         "rfeature" | "rmapfeat" => Some(vec![EventData { 
             name: "feature", nation: None,
-            region: Some(event.origin.as_ref()?.clone()) 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
         }]),
         "ndel" | "rdel" | "ldel" => Some(vec![EventData { 
             name: "delegate",
             nation: Some(event.receptor.as_ref()?.clone()), 
-            region: Some(event.origin.as_ref()?.clone()) 
-        }]),
-        "nfound" | "nrefound" => Some(vec![EventData { 
-            name: "found",
-            nation: Some(event.actor.as_ref()?.clone()), 
-            region: Some(event.origin.as_ref()?.clone()) 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
         }]),
+        "nfound" | "nrefound" => {
+            let nation = event.actor.as_ref()?;
+            let origin = event.origin.as_ref()?;
+
+            if tracks_residency(origin, config, &cache).await {
+                cache.nations.write().await.insert(nation.clone(), NationInfo { 
+                    region: origin.clone(), arrived: Some(event.time), ..Default::default() 
+                });
+            }
+
+            Some(vec![EventData { 
+                name: "found",
+                nation: Some(nation.clone()), 
+                region: Some(origin.clone()), ..Default::default() 
+            }])
+        },
         "wapply" => Some(vec![EventData { 
             name: "apply",
             nation: Some(event.actor.as_ref()?.clone()), 
-            region: Some(event.origin.as_ref()?.clone()) 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+        }]),
+        "rnewgov" | "stgovadd" | "govabd" | "fngovrem" | "rgcte" => Some(vec![EventData { 
+            name: "governor",
+            nation: event.actor.clone(), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+        }]),
+        "roappoint" | "rodismiss" | "rorename" | "roauth" => Some(vec![EventData { 
+            name: match event.category.as_str() {
+                "roappoint" => "ro-appoint",
+                "rodismiss" => "ro-dismiss",
+                "rorename" => "ro-rename",
+                _ => "ro-authority"
+            },
+            nation: Some(event.receptor.as_ref()?.clone()), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+        }]),
+        "rpwadd" | "rpwrem" => Some(vec![EventData { 
+            name: "password",
+            nation: event.actor.clone(), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+        }]),
+        "embreq" | "embcon" | "embcancel" | "embclose" | "embrej" | "embabort" => {
+            let origin = event.origin.as_ref()?;
+            let destination = event.destination.as_ref()?;
+            let name = match event.category.as_str() {
+                "embreq" => "embassy-request",
+                "embcon" | "embcancel" => "embassy-open",
+                _ => "embassy-close"
+            };
+
+            // Embassies concern both regions, so both get the happening
+            Some(vec![EventData { 
+                name, nation: event.actor.clone(), region: Some(origin.clone()), other_region: Some(destination.clone()), 
+                ..Default::default() 
+            }])
+        },
+        "rwfe" => Some(vec![EventData { 
+            name: "wfe",
+            nation: event.actor.clone(), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+        }]),
+        "ban" | "unban" | "eject" | "banject" => {
+            let nation = event.receptor.as_ref()?;
+            let origin = event.origin.as_ref()?;
+            let name = match event.category.as_str() {
+                "ban" => "ban",
+                "unban" => "unban",
+                "eject" => "eject",
+                _ => "banject"
+            };
+
+            // Ejected nations lose their endorsements, like when moving
+            if let ("eject" | "banject", Some(destination)) = (name, &event.destination) {
+                record_move(nation, origin, destination, event.time, config, &cache).await;
+            }
+
+            Some(vec![EventData { 
+                name, nation: Some(nation.clone()), region: Some(origin.clone()),
+                wa: cache.wa_nations.read().await.contains(nation), ..Default::default() 
+            }])
+        },
+        "rflag" => Some(vec![EventData { 
+            name: "flag",
+            nation: event.actor.clone(), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
+        }]),
+        "rbanner" => Some(vec![EventData { 
+            name: "banner",
+            nation: event.actor.clone(), 
+            region: Some(event.origin.as_ref()?.clone()), ..Default::default() 
         }]),
-        "rsfloor" => Some(vec![EventData { name: "wa-floor", nation: None, region: None }]),
-        "rssubmit" => Some(vec![EventData { name: "wa-submit", nation: None, region: None }]),
-        "rspass" => Some(vec![EventData { name: "wa-pass", nation: None, region: None }]),
-        "rsfail" => Some(vec![EventData { name: "wa-fail", nation: None, region: None }]),
-        "rdiscard" => Some(vec![EventData { name: "wa-discard", nation: None, region: None }]),
+        "rsfloor" | "rssubmit" | "rspass" | "rsfail" | "rdiscard" | "rsapprove" => {
+            let name = match event.category.as_str() {
+                "rsfloor" => "wa-floor",
+                "rssubmit" => "wa-submit",
+                "rspass" => "wa-pass",
+                "rsfail" => "wa-fail",
+                "rdiscard" => "wa-discard",
+                _ => "wa-approve"
+            };
+
+            // Votes are tracked from when a resolution reaches the floor until it's decided
+            if let Some(chamber) = event.data.first() {
+                match name {
+                    // Nobody has voted yet, so every watched delegate's vote in the first poll is reported
+                    "wa-floor" => {
+                        let votes = WaVotes {
+                            name: event.data.get(1).cloned().unwrap_or_default(),
+                            votes_for: 0, votes_against: 0, delegates: HashMap::new()
+                        };
+
+                        cache.vote_tallies.write().await.insert(chamber.clone(), VoteTally { event: event.clone(), votes: Some(votes), lead: None });
+                    },
+                    "wa-pass" | "wa-fail" | "wa-discard" => {
+                        cache.vote_tallies.write().await.remove(chamber);
+                    },
+                    _ => {}
+                }
+            }
+
+            // Approvals are also sent to the approving delegate's region
+            let region = if name == "wa-approve" { event.origin.clone() } else { None };
+
+            let mut result = vec![EventData { name, nation: None, region: region.clone(), ..Default::default() }];
+            if let Some(name) = council_category(name, event.data.first()) {
+                result.push(EventData { name, nation: None, region, ..Default::default() });
+            }
+
+            Some(result)
+        },
         _ => {
             Some(vec![])
         }
     }
 }
 
+/// Collects the nations and regions linked with `[nation]` and `[region]` tags, leaving out quoted posts.
+fn collect_mentions(tags: &[Tag<'_>], nations: &mut HashSet<String>, regions: &mut HashSet<String>) {
+    for tag in tags {
+        match tag {
+            Tag::Nation(name) => { nations.insert(name.to_lowercase().replace(' ', "_")); },
+            Tag::Region(name) => { regions.insert(name.to_lowercase().replace(' ', "_")); },
+            Tag::Quote(_) => {},
+            tag => collect_mentions(tag.children(), nations, regions),
+        }
+    }
+}
+
+/// A region or nation linked from a post, and the config watching it.
+/// `region` is the linked region, or for a watched nation, the region watching it (if any).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Mention {
+    target: ConfigTarget,
+    region: Option<String>,
+    nation: Option<String>,
+}
+
+fn watches_nation(region_config: &RegionConfig, nation: &str) -> bool {
+    region_config.events.get("mentioned").is_some_and(|event| event.nations.iter().any(|n| n == nation))
+}
+
+/// Finds the configs watching the regions and nations linked from a post on `origin`'s RMB.
+/// Linked regions go to their own config and the tags they're in, watched nations to every config listing
+/// them in `nations`. Each config gets at most one mention per post.
+fn find_mentions(
+    origin: &str, content: &str, config: &Config, tag_cloud: &HashMap<String, HashSet<String>>
+) -> Vec<Mention> {
+    let mut nations = HashSet::new();
+    let mut regions = HashSet::new();
+    collect_mentions(&nscode::parse(content), &mut nations, &mut regions);
+
+    let mut nations: Vec<String> = nations.into_iter().collect();
+    let mut regions: Vec<String> = regions.into_iter().filter(|region| region != origin).collect();
+    nations.sort();
+    regions.sort();
+
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    let mut push = |target: ConfigTarget, region: Option<&String>, nation: Option<&String>| {
+        if seen.insert(target.clone()) {
+            result.push(Mention { target, region: region.cloned(), nation: nation.cloned() });
+        }
+    };
+
+    for region in &regions {
+        if config.regions.get(region).is_some_and(|region_config| region_config.events.contains_key("mentioned")) {
+            push(ConfigTarget::Region(region.clone()), Some(region), None);
+        }
+
+        for (tag, tag_regions) in tag_cloud.iter().sorted_by_key(|(tag, _)| *tag) {
+            if !tag_regions.contains(region) { continue; }
+
+            if let Some(tag_config) = config.tags.get(tag)
+            && tag_config.events.contains_key("mentioned") && !tag_config.exclude.contains(region) {
+                push(ConfigTarget::Tag(tag.clone()), Some(region), None);
+            }
+        }
+    }
+
+    for nation in &nations {
+        for (region, region_config) in config.regions.iter().sorted_by_key(|(region, _)| *region) {
+            if region != origin && watches_nation(region_config, nation) {
+                push(ConfigTarget::Region(region.clone()), Some(region), Some(nation));
+            }
+        }
+
+        for (tag, tag_config) in config.tags.iter().sorted_by_key(|(tag, _)| *tag) {
+            if watches_nation(tag_config, nation) {
+                push(ConfigTarget::Tag(tag.clone()), None, Some(nation));
+            }
+        }
+
+        if config.world.as_ref().is_some_and(|world| watches_nation(world, nation)) {
+            push(ConfigTarget::World, None, Some(nation));
+        }
+    }
+
+    result
+}
+
+/// Turns the regions and nations linked from an RMB post into `mentioned` happenings, each with the config
+/// to send it through. The linked region is the event's destination and the linked nation (if any) its receptor.
+pub async fn classify_mentions(event: &Event, config: &Config, cache: &NSCache) -> Vec<(ConfigTarget, EventData, Event)> {
+    if event.category != "rmbpost" { return Vec::new(); }
+    let (Some(origin), Some(content)) = (event.origin.as_ref(), event.data.get(1)) else { return Vec::new() };
+
+    let mentions = find_mentions(origin, content, config, &*cache.tag_cloud.read().await);
+
+    mentions.into_iter().map(|mention| (
+        mention.target,
+        EventData { name: "mentioned", nation: mention.nation.clone(), region: mention.region.clone(), ..Default::default() },
+        Event { 
+            category: "rmbmention".to_string(), receptor: mention.nation, 
+            destination: mention.region, ..event.clone() 
+        }
+    )).collect()
+}
+
+/// Whether the other side took the lead since the last poll. Compared against the last side ahead,
+/// so a flip that passes through a tie between polls still counts.
+fn lead_changed(previous_lead: Option<Ordering>, votes: &WaVotes) -> bool {
+    matches!((previous_lead, votes.lead()), (Some(previous), Some(lead)) if lead != previous)
+}
+
+/// The watched delegates whose vote changed since the last poll, with their new vote.
+fn changed_votes(previous: &WaVotes, votes: &WaVotes, watched: &HashSet<String>) -> Vec<(String, &'static str)> {
+    watched.iter().sorted().filter_map(|nation| {
+        let vote = votes.delegates.get(nation);
+        if vote == previous.delegates.get(nation) { return None; }
+
+        Some((nation.clone(), match vote {
+            Some(true) => "for",
+            Some(false) => "against",
+            None => "withdrawn"
+        }))
+    }).collect()
+}
+
+/// Starts tracking the votes of a resolution that was already at vote when Bubble started, as if its `rsfloor`
+/// had been seen. Chambers that are already tracked are left alone.
+pub async fn track_resolution_at_vote(chamber: &str, votes: WaVotes, time: u64, cache: &NSCache) {
+    let event = Event {
+        category: "rsfloor".to_string(), actor: None, receptor: None, origin: None, destination: None,
+        data: vec![chamber.to_string(), votes.name.clone()], time
+    };
+
+    cache.vote_tallies.write().await.entry(chamber.to_string()).or_insert_with(|| VoteTally {
+        event, lead: votes.lead(), votes: Some(votes)
+    });
+}
+
+/// Yields a vote tally for a resolution at vote, plus happenings for a change in the lead
+/// and for watched delegates changing their vote since the last poll.
+pub fn classify_votes(tally: &VoteTally, votes: &WaVotes, watched: &HashSet<String>, time: u64) -> Vec<(EventData, Event)> {
+    let mut result = Vec::new();
+    let Some(chamber) = tally.event.data.first() else { return result };
+
+    let totals = vec![chamber.clone(), votes.name.clone(), votes.votes_for.to_string(), votes.votes_against.to_string()];
+    let mut push = |name: &'static str, event: Event| {
+        result.push((EventData { name, ..Default::default() }, event.clone()));
+        if let Some(name) = council_category(name, Some(chamber)) {
+            result.push((EventData { name, ..Default::default() }, event));
+        }
+    };
+
+    push("wa-tally", Event { category: "watally".to_string(), data: totals.clone(), time, ..tally.event.clone() });
+
+    let Some(previous) = &tally.votes else { return result };
+
+    if lead_changed(tally.lead, votes) {
+        push("wa-lead", Event { category: "walead".to_string(), data: totals.clone(), time, ..tally.event.clone() });
+    }
+
+    for (nation, vote) in changed_votes(previous, votes, watched) {
+        let mut data = totals.clone();
+        data.push(vote.to_string());
+
+        push("wa-vote", Event {
+            category: "wavote".to_string(), actor: Some(nation), data, time, ..tally.event.clone()
+        });
+    }
+
+    result
+}
+
+/// Yields an `sc-target` happening for each config concerned by a Security Council proposal: the targeted region,
+/// the targeted nation's region, regions and tags watching the targeted nation in `nations`, and tags containing
+/// any of those regions. Each config gets the happening once.
+pub async fn classify_sc_target(
+    event: &Event, target: &ScTarget, config: &Config, cache: &NSCache
+) -> Vec<(ConfigTarget, EventData, Event)> {
+    let mut regions: Vec<String> = Vec::new();
+    let proposal = if event.category == "rssubmit" { event.data.get(2) } else { event.data.get(1) };
+    let Some(proposal) = proposal else { return Vec::new() };
+
+    let stage = if event.category == "rssubmit" { "submitted" } else { "at vote" };
+    let data = vec![proposal.clone(), target.category.clone(), stage.to_string()];
+
+    let (receptor, destination) = match target.option.split_once(':') {
+        Some(("R", region)) => {
+            regions.push(region.to_string());
+            (None, Some(region.to_string()))
+        },
+        Some(("N", nation)) => {
+            if let Some(info) = cache.nations.read().await.get(nation) && !info.region.is_empty() {
+                regions.push(info.region.clone());
+            }
+
+            for (region, region_config) in &config.regions {
+                if region_config.events.get("sc-target").is_some_and(|e| e.nations.iter().any(|n| n == nation))
+                && !regions.contains(region) {
+                    regions.push(region.clone());
+                }
+            }
+
+            (Some(nation.to_string()), None)
+        },
+        _ => return Vec::new()
+    };
+
+    let event = Event { category: "sctarget".to_string(), receptor: receptor.clone(), destination, data, ..event.clone() };
+
+    let mut result: Vec<_> = regions.iter().map(|region| (
+        ConfigTarget::Region(region.clone()),
+        EventData { name: "sc-target", nation: receptor.clone(), region: Some(region.clone()), ..Default::default() },
+        event.clone()
+    )).collect();
+
+    let tag_cloud = cache.tag_cloud.read().await;
+    for (tag, tag_config) in config.tags.iter().sorted_by_key(|(tag, _)| *tag) {
+        let Some(event_config) = tag_config.events.get("sc-target") else { continue };
+        let tagged = tag_cloud.get(tag);
+
+        let concerned = regions.iter().any(|region| {
+            tagged.is_some_and(|tagged| tagged.contains(region)) && !tag_config.exclude.contains(region)
+        }) || receptor.as_ref().is_some_and(|nation| event_config.nations.contains(nation));
+
+        if concerned {
+            result.push((
+                ConfigTarget::Tag(tag.clone()),
+                EventData { name: "sc-target", nation: receptor.clone(), ..Default::default() },
+                event.clone()
+            ));
+        }
+    }
+
+    result
+}
+
 pub async fn check_and_update_tag_cloud(event: &Event, cache: Arc<NSCache>) {
     match event.category.as_str() {
         "rgcte" | "govabd" => {
@@ -142,4 +640,287 @@ pub async fn check_and_update_tag_cloud(event: &Event, cache: Arc<NSCache>) {
         }
         _ => {}
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config_str;
+
+    const CONFIG: &str = r#"
+        [input]
+        exchange_name = "akari_events"
+
+        [region.the_north_pacific]
+        mentioned = { nations = ["testlandia"] }
+
+        [region.the_south_pacific]
+        mentioned = {}
+
+        [region.lazarus]
+        rmb = {}
+
+        [tag.feeders]
+        exclude = ["the_east_pacific"]
+        mentioned = { nations = ["testlandia"] }
+
+        [world]
+        mentioned = { nations = ["testlandia", "maxtopia"] }
+    "#;
+
+    fn tag_cloud() -> HashMap<String, HashSet<String>> {
+        HashMap::from([(
+            "feeders".to_string(),
+            HashSet::from(["the_south_pacific".to_string(), "the_east_pacific".to_string()])
+        )])
+    }
+
+    fn mentions(origin: &str, content: &str) -> Vec<Mention> {
+        find_mentions(origin, content, &parse_config_str(CONFIG).unwrap(), &tag_cloud())
+    }
+
+    fn votes(votes_for: u64, votes_against: u64, delegates: &[(&str, bool)]) -> WaVotes {
+        WaVotes {
+            name: "Repeal \"Example\"".to_string(), votes_for, votes_against,
+            delegates: delegates.iter().map(|(nation, vote)| (nation.to_string(), *vote)).collect()
+        }
+    }
+
+    fn event(category: &str, data: &[&str]) -> Event {
+        Event {
+            category: category.to_string(), actor: Some("testlandia".to_string()), receptor: Some("maxtopia".to_string()),
+            origin: Some("lazarus".to_string()), destination: Some("the_north_pacific".to_string()),
+            data: data.iter().map(|item| item.to_string()).collect(), time: 1000
+        }
+    }
+
+    async fn classify(event: &Event, cache: Arc<NSCache>) -> Vec<EventData> {
+        classify_event(event, &parse_config_str(CONFIG).unwrap(), cache).await.unwrap()
+    }
+
+    fn names(result: &[EventData]) -> Vec<&'static str> {
+        result.iter().map(|data| data.name).collect()
+    }
+
+    fn mention(target: ConfigTarget, region: Option<&str>, nation: Option<&str>) -> Mention {
+        Mention { target, region: region.map(str::to_string), nation: nation.map(str::to_string) }
+    }
+
+    #[test]
+    fn links_are_collected_outside_quotes() {
+        let mut nations = HashSet::new();
+        let mut regions = HashSet::new();
+        let tags = nscode::parse(
+            "[b][nation]Test Landia[/nation][/b] [region]The South Pacific[/region] \
+             [quote=maxtopia;1][nation]maxtopia[/nation][/quote]"
+        );
+        collect_mentions(&tags, &mut nations, &mut regions);
+
+        assert_eq!(nations, HashSet::from(["test_landia".to_string()]));
+        assert_eq!(regions, HashSet::from(["the_south_pacific".to_string()]));
+    }
+
+    #[test]
+    fn linked_regions_go_to_their_region_and_tags() {
+        assert_eq!(mentions("lazarus", "[region]The South Pacific[/region]"), vec![
+            mention(ConfigTarget::Region("the_south_pacific".into()), Some("the_south_pacific"), None),
+            mention(ConfigTarget::Tag("feeders".into()), Some("the_south_pacific"), None),
+        ]);
+    }
+
+    #[test]
+    fn tags_see_linked_regions_without_a_region_config() {
+        assert_eq!(mentions("lazarus", "[region]the_south_pacific[/region] [region]the_east_pacific[/region]").len(), 2);
+        assert!(mentions("lazarus", "[region]the_east_pacific[/region]").is_empty());
+    }
+
+    #[test]
+    fn posts_linking_their_own_region_are_ignored() {
+        assert!(mentions("the_south_pacific", "[region]the_south_pacific[/region]").is_empty());
+    }
+
+    #[test]
+    fn each_config_gets_one_mention_per_post() {
+        assert_eq!(mentions("lazarus", "[nation]testlandia[/nation] [nation]maxtopia[/nation]"), vec![
+            mention(ConfigTarget::World, None, Some("maxtopia")),
+            mention(ConfigTarget::Region("the_north_pacific".into()), Some("the_north_pacific"), Some("testlandia")),
+            mention(ConfigTarget::Tag("feeders".into()), None, Some("testlandia")),
+        ]);
+    }
+
+    #[test]
+    fn lead_changes_are_found_through_ties() {
+        assert!(lead_changed(Some(Ordering::Greater), &votes(10, 12, &[])));
+        assert!(!lead_changed(Some(Ordering::Greater), &votes(12, 10, &[])));
+        assert!(!lead_changed(Some(Ordering::Greater), &votes(10, 10, &[])));
+        assert!(!lead_changed(None, &votes(10, 12, &[])));
+    }
+
+    #[test]
+    fn only_watched_vote_changes_are_found() {
+        let watched = HashSet::from(["testlandia".to_string(), "maxtopia".to_string(), "elsewhere".to_string()]);
+        let previous = votes(10, 5, &[("testlandia", true), ("maxtopia", true), ("unwatched", true)]);
+        let current = votes(10, 5, &[("testlandia", false), ("elsewhere", true), ("unwatched", false)]);
+
+        assert_eq!(changed_votes(&previous, &current, &watched), vec![
+            ("elsewhere".to_string(), "for"),
+            ("maxtopia".to_string(), "withdrawn"),
+            ("testlandia".to_string(), "against"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn influence_is_reset_on_relocation() {
+        let config = parse_config_str(CONFIG).unwrap();
+        let cache = NSCache::new();
+        cache.nations.write().await.insert("testlandia".to_string(), NationInfo {
+            region: "lazarus".to_string(), influence: "Eminence Grise".to_string(), ..Default::default()
+        });
+
+        let (leaving, _) = record_move("testlandia", "lazarus", "the_north_pacific", 1000, &config, &cache).await;
+        assert_eq!(leaving.influence.as_deref(), Some("Eminence Grise"));
+
+        let (leaving, _) = record_move("testlandia", "the_north_pacific", "lazarus", 2000, &config, &cache).await;
+        assert_eq!(leaving.influence, None);
+        assert_eq!(leaving.since, Some(1000));
+    }
+
+    #[tokio::test]
+    async fn categories_are_classified_by_name() {
+        let cases: &[(&str, &[&str])] = &[
+            ("ncte", &["cte"]),
+            ("wadmit", &["admit"]),
+            ("wresign", &["resign"]),
+            ("wkick", &["wakick"]),
+            ("endo", &["endo"]),
+            ("unendo", &["unendo"]),
+            ("move", &["join", "leave"]),
+            ("rmbpost", &["rmb"]),
+            ("rmbdelete", &["rmb-suppress"]),
+            ("rupdate", &["update"]),
+            ("rmapfeat", &["feature"]),
+            ("rdel", &["delegate"]),
+            ("nrefound", &["found"]),
+            ("wapply", &["apply"]),
+            ("govabd", &["governor"]),
+            ("roappoint", &["ro-appoint"]),
+            ("rodismiss", &["ro-dismiss"]),
+            ("rorename", &["ro-rename"]),
+            ("roauth", &["ro-authority"]),
+            ("rpwrem", &["password"]),
+            ("embreq", &["embassy-request"]),
+            ("embcancel", &["embassy-open"]),
+            ("embabort", &["embassy-close"]),
+            ("rwfe", &["wfe"]),
+            ("banject", &["banject"]),
+            ("rflag", &["flag"]),
+            ("rbanner", &["banner"]),
+            ("rsfloor", &["wa-floor"]),
+            ("rsapprove", &["wa-approve"]),
+            ("nunknown", &[]),
+        ];
+
+        for (category, expected) in cases {
+            let result = classify(&event(category, &[]), NSCache::new()).await;
+            assert_eq!(names(&result), *expected, "{category}");
+        }
+    }
+
+    #[tokio::test]
+    async fn wa_members_get_their_own_categories() {
+        for (category, expected) in [("ncte", vec!["wacte"]), ("move", vec!["wajoin", "waleave"])] {
+            let cache = NSCache::new();
+            cache.wa_nations.write().await.extend(["testlandia".to_string(), "maxtopia".to_string()]);
+            assert_eq!(names(&classify(&event(category, &[]), cache).await), expected, "{category}");
+        }
+    }
+
+    #[tokio::test]
+    async fn wa_happenings_are_also_sent_per_council() {
+        let cases = [
+            ("rsfloor", "General Assembly", ["wa-floor", "ga-floor"]),
+            ("rssubmit", "Security Council", ["wa-submit", "sc-submit"]),
+            ("rspass", "General Assembly", ["wa-pass", "ga-pass"]),
+            ("rsfail", "Security Council", ["wa-fail", "sc-fail"]),
+            ("rdiscard", "General Assembly", ["wa-discard", "ga-discard"]),
+            ("rsapprove", "Security Council", ["wa-approve", "sc-approve"]),
+        ];
+
+        for (category, chamber, expected) in cases {
+            let result = classify(&event(category, &[chamber]), NSCache::new()).await;
+            assert_eq!(names(&result), expected, "{category}");
+
+            let region = if category == "rsapprove" { Some("lazarus") } else { None };
+            assert!(result.iter().all(|data| data.region.as_deref() == region), "{category}");
+        }
+    }
+
+    #[tokio::test]
+    async fn resolutions_are_tallied_while_at_vote() {
+        let cache = NSCache::new();
+
+        classify(&event("rsfloor", &["Security Council"]), cache.clone()).await;
+        assert!(cache.vote_tallies.read().await.contains_key("Security Council"));
+
+        classify(&event("rspass", &["Security Council"]), cache.clone()).await;
+        assert!(cache.vote_tallies.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn first_poll_after_floor_reports_delegate_votes() {
+        let cache = NSCache::new();
+        classify(&event("rsfloor", &["General Assembly", "Repeal \"Example\""]), cache.clone()).await;
+
+        let tally = cache.vote_tallies.read().await["General Assembly"].clone();
+        let watched = HashSet::from(["testlandia".to_string(), "maxtopia".to_string()]);
+        let result = classify_votes(&tally, &votes(12, 10, &[("testlandia", true)]), &watched, 2000);
+
+        let names: Vec<_> = result.iter().map(|(data, _)| data.name).collect();
+        assert_eq!(names, ["wa-tally", "ga-tally", "wa-vote", "ga-vote"]);
+        assert_eq!(result[2].1.actor.as_deref(), Some("testlandia"));
+        assert_eq!(result[2].1.data.last().map(String::as_str), Some("for"));
+    }
+
+    #[tokio::test]
+    async fn resolutions_at_vote_on_startup_are_tallied() {
+        let cache = NSCache::new();
+        track_resolution_at_vote("General Assembly", votes(12, 10, &[("testlandia", true)]), 1000, &cache).await;
+
+        let tally = cache.vote_tallies.read().await["General Assembly"].clone();
+        assert_eq!(tally.event.data, ["General Assembly", "Repeal \"Example\""]);
+        assert_eq!(tally.lead, Some(Ordering::Greater));
+
+        // The first poll only yields the tally, as nothing changed since startup
+        let result = classify_votes(&tally, &votes(12, 10, &[("testlandia", true)]), &HashSet::from(["testlandia".to_string()]), 2000);
+        assert_eq!(result.iter().map(|(data, _)| data.name).collect::<Vec<_>>(), ["wa-tally", "ga-tally"]);
+    }
+
+    #[tokio::test]
+    async fn embassies_are_sent_to_both_regions() {
+        let result = classify(&event("embcon", &[]), NSCache::new()).await;
+
+        assert_eq!(names(&result), ["embassy-open"]);
+        for data in result {
+            assert_eq!(data.region.as_deref(), Some("lazarus"));
+            assert_eq!(data.other_region.as_deref(), Some("the_north_pacific"));
+        }
+    }
+
+    #[tokio::test]
+    async fn ejections_move_the_ejected_nation() {
+        let cache = NSCache::new();
+        cache.wa_nations.write().await.insert("maxtopia".to_string());
+
+        let result = classify(&event("eject", &[]), cache.clone()).await;
+        assert_eq!(names(&result), ["eject"]);
+        assert_eq!(result[0].nation.as_deref(), Some("maxtopia"));
+        assert_eq!(result[0].region.as_deref(), Some("lazarus"));
+        assert!(result[0].wa);
+        assert_eq!(cache.nations.read().await["maxtopia"].region, "the_north_pacific");
+
+        let banned = Event { destination: None, ..event("eject", &[]) };
+        let cache = NSCache::new();
+        classify(&banned, cache.clone()).await;
+        assert!(cache.nations.read().await.is_empty());
+    }
+}
@@ -9,12 +9,14 @@ mod render;
 mod worker;
 mod cache;
 mod events;
+mod dump;
 
 use std::{sync::Arc, process::exit, error::Error};
 
-use log::{error, warn};
+use log::{error, info, warn};
 use serenity::all::Http;
-use tokio::sync::{mpsc::Sender};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc::{Sender, error::TrySendError};
 
 use caramel::log::setup_log;
 use caramel::ns::{api::Client, UserAgent};
@@ -22,9 +24,9 @@ use caramel::akari;
 use caramel::types::akari::Event;
 
 use crate::cache::NSCache;
-use crate::config::Config;
+use crate::config::{Config, OutputConfig};
 use crate::worker::NSQuery;
-use crate::events::{check_and_update_tag_cloud, classify_event};
+use crate::events::{EventData, check_and_update_tag_cloud, classify_event, classify_mentions};
 
 const PROGRAM: &str = "bubble";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -39,10 +41,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let user_agent = UserAgent::read_from_env(PROGRAM, VERSION, AUTHOR);
 
-    let config = config::parse_config(CONFIG_PATH).unwrap_or_else(|err| {
+    let config = Arc::new(config::parse_config(CONFIG_PATH).unwrap_or_else(|err| {
         error!("Failed to read config file: {err}");
         exit(1);
-    });
+    }));
 
     let url = std::env::var("RABBITMQ_URL").unwrap_or_else(|err| {
         error!("Missing RABBITMQ_URL environment variable: {err}");
@@ -63,6 +65,34 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }));
 
     let cache = NSCache::new();
+    let mut loaded_snapshot = false;
+
+    if let Some(path) = &config.cache.snapshot {
+        if std::path::Path::new(path).exists() {
+            loaded_snapshot = cache.load_snapshot(path, &config).await.unwrap_or_else(|err| {
+                warn!("Failed to load cache snapshot from {path}: {err}");
+                false
+            });
+        }
+
+        worker::spawn_snapshot_worker(cache.clone(), path.clone(), config.cache.snapshot_interval);
+    }
+
+    if let Some(path) = config.cache.nations_dump.clone() {
+        match tokio::task::spawn_blocking(move || dump::load_nations_dump(&path)).await? {
+            Ok((wa_nations, nations)) => {
+                info!("Loaded {} nations ({} in the WA) from the nations dump", nations.len(), wa_nations.len());
+
+                // The snapshot is at most a few minutes old, while the dump can be up to a day old
+                if !loaded_snapshot {
+                    *cache.wa_nations.write().await = wa_nations;
+                }
+
+                *cache.nations.write().await = nations;
+            },
+            Err(err) => warn!("Failed to load nations dump: {err}")
+        }
+    }
 
     let mut ns_tx = worker::spawn_ns_worker(client.clone(), cache.clone());
 
@@ -70,12 +100,63 @@ async fn main() -> Result<(), Box<dyn Error>> {
         error!("Failed to trigger WA nation update: {err}");
     });
 
-    cache.run_tag_query(&mut ns_tx, &config).await;
+    if cache.should_run_startup_tag_query(&config, loaded_snapshot).await {
+        cache.run_tag_query(&mut ns_tx, &config).await;
+    }
 
-    let http = Http::new("");
+    // The NS worker goes through these at the API rate limit, so they're queued from their own task
+    // to let the feed start right away
+    let regions: Vec<String> = config.regions.keys().cloned().collect();
+    let startup_tx = ns_tx.clone();
+    tokio::spawn(async move {
+        for region in regions {
+            startup_tx.send(NSQuery::UpdateResidents(region.clone())).await.unwrap_or_else(|err| {
+                error!("Failed to trigger resident update: {err}");
+            });
 
-    while let Some(event) = akari::consume(&mut consumer).await {
-        process_event(&http, event, &config, cache.clone(), &user_agent, &mut ns_tx).await;
+            startup_tx.send(NSQuery::UpdateDelegate(region.clone())).await.unwrap_or_else(|err| {
+                error!("Failed to trigger delegate update: {err}");
+            });
+        }
+    });
+
+    let http = Arc::new(Http::new(""));
+
+    worker::spawn_digest_worker(http.clone(), cache.clone());
+
+    if let Some(period) = config.tally_interval() {
+        worker::spawn_tally_worker(client.clone(), http.clone(), config.clone(), cache.clone(), user_agent.clone(), period);
+    }
+
+    let target_tx = config.wants_event("sc-target").then(|| {
+        worker::spawn_target_worker(client.clone(), http.clone(), config.clone(), cache.clone(), user_agent.clone())
+    });
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    loop {
+        tokio::select! {
+            event = akari::consume(&mut consumer) => {
+                let Some(event) = event else { break };
+                process_event(&http, event, &config, cache.clone(), &user_agent, &mut ns_tx, target_tx.as_ref()).await;
+            },
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+
+    // Pending digests only live in memory, so send them now rather than losing them
+    for digest in cache.take_all_digests().await {
+        rmb::output_rmb_digest(&http, &digest).await.unwrap_or_else(|err| {
+            error!("Failed to send RMB digest for {} to webhook: {err}", digest.region);
+        });
+    }
+
+    if let Some(path) = &config.cache.snapshot {
+        cache.save_snapshot(path).await.unwrap_or_else(|err| {
+            error!("Failed to save cache snapshot to {path}: {err}");
+        });
     }
 
     Ok(())
@@ -85,7 +166,8 @@ async fn process_event(
     http: &Http, event: Event, config: &Config, 
     cache: Arc<NSCache>,
     user_agent: &UserAgent, 
-    ns_tx: &mut Sender<NSQuery>
+    ns_tx: &mut Sender<NSQuery>,
+    target_tx: Option<&Sender<Event>>
 ) {
     if event.category == "connmiss" {
         ns_tx.send(NSQuery::UpdateWA).await.unwrap_or_else(|err| {
@@ -96,34 +178,95 @@ async fn process_event(
     }
 
     check_and_update_tag_cloud(&event, cache.clone()).await;
-    let Some(event_data) = classify_event(&event, cache.clone()).await else {
+    let Some(event_data) = classify_event(&event, config, cache.clone()).await else {
         warn!("Malformed event {}: {:?}", event.category, event);
         return;
     };
 
+    // SC proposal targets need an API request, so they're looked up and sent separately
+    if matches!(event.category.as_str(), "rssubmit" | "rsfloor") 
+    && event.data.first().is_some_and(|chamber| chamber == "Security Council") && let Some(target_tx) = target_tx {
+        target_tx.send(event.clone()).await.unwrap_or_else(|err| {
+            error!("Failed to trigger SC target lookup: {err}");
+        });
+    }
+
     if cache.should_run_tag_query().await {
         cache.run_tag_query(ns_tx, config).await;
     }
 
     for data in event_data {
-        if let Some(region) = &data.region {
-            if let Some(output_config) = config.get_region_event(region, data.name) {
-                output::output_event(http, data.name, &output_config, &event, &user_agent).await.unwrap_or_else(|err| {
-                    error!("Failed to send event {event:?} to webhook: {err}");
-                });
-            }
-
-            for output_config in config.get_tag_events(cache.clone(), region, data.name).await {
-                output::output_event(http, data.name, &output_config, &event, &user_agent).await.unwrap_or_else(|err| {
-                    error!("Failed to send event {event:?} to webhook: {err}");
-                });
-            }
+        // Refresh the delegate and its endorsements, so endorsement counts stay accurate.
+        // This is skipped rather than waited for when the NS worker is busy, so the feed isn't held up
+        if data.name == "delegate" && let Some(region) = &data.region && config.regions.contains_key(region) {
+            ns_tx.try_send(NSQuery::UpdateDelegate(region.clone())).unwrap_or_else(|err| {
+                warn!("Failed to trigger delegate update: {err}");
+            });
         }
 
-        if let Some(output_config) = config.get_world_event(data.name) {
-            output::output_event(http, data.name, &output_config, &event, &user_agent).await.unwrap_or_else(|err| {
-                error!("Failed to send event {event:?} to webhook: {err}");
+        route_event(http, &data, &event, config, cache.clone(), user_agent, ns_tx).await;
+    }
+
+    for (target, data, mention) in classify_mentions(&event, config, &cache).await {
+        if let Some(output_config) = config.get_target_event(&target, data.name) {
+            output::output_event(http, &data, &output_config, &mention, &cache, user_agent).await.unwrap_or_else(|err| {
+                error!("Failed to send event {mention:?} to webhook: {err}");
             });
         }
     }
-}
\ No newline at end of file
+}
+
+/// Sends a classified happening to every region, tag and world output configured for it.
+async fn route_event(
+    http: &Http, data: &EventData, event: &Event, config: &Config,
+    cache: Arc<NSCache>,
+    user_agent: &UserAgent,
+    ns_tx: &Sender<NSQuery>
+) {
+    // Happenings between two regions go to both, but to tags containing both only once
+    let regions: Vec<&String> = data.region.iter().chain(&data.other_region).collect();
+
+    let mut outputs: Vec<OutputConfig> = regions.iter()
+        .filter_map(|region| config.get_region_event(region, data.name))
+        .collect();
+
+    outputs.extend(config.get_tag_events(cache.clone(), &regions, data.name).await);
+
+    // Only visible text is matched: quotes are left out so quoting a post doesn't trigger its alerts again,
+    // and link targets and other NSCode aren't text anyone reads
+    if data.name == "rmb" && let Some(region) = &data.region && let Some(content) = event.data.get(1) {
+        let text = render::plain_text(&nscode::parse(content));
+        outputs.extend(config.get_rmb_alerts(cache.clone(), region, &text).await);
+    }
+
+    outputs.extend(config.get_world_event(data.name));
+
+    if data.name == "rmb" && let Some(nation) = &data.nation && outputs.iter().any(|output_config| output_config.flag) {
+        request_flag(nation, &cache, ns_tx).await;
+    }
+
+    for output_config in &outputs {
+        output::output_event(http, data, output_config, event, &cache, user_agent).await.unwrap_or_else(|err| {
+            match output_config.alert {
+                Some(_) => error!("Failed to send alert for event {event:?} to webhook: {err}"),
+                None => error!("Failed to send event {event:?} to webhook: {err}"),
+            }
+        });
+    }
+}
+
+/// Queues a lookup of a nation's flag if it isn't cached or already being looked up.
+/// Flags are only cosmetic, so they're dropped when the NS worker is busy rather than holding up the feed.
+async fn request_flag(nation: &str, cache: &NSCache, ns_tx: &Sender<NSQuery>) {
+    if cache.flags.read().await.get(nation).is_some() || !cache.pending_flags.write().await.insert(nation.to_string()) {
+        return;
+    }
+
+    if let Err(err) = ns_tx.try_send(NSQuery::UpdateFlag(nation.to_string())) {
+        cache.pending_flags.write().await.remove(nation);
+
+        if let TrySendError::Closed(_) = err {
+            error!("Failed to trigger flag update: {err}");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use pest::{Parser, iterators::{Pairs, Pair}};
+use pest::{Parser, iterators::Pair};
 use pest_derive::Parser;
 use bbx::BBParser;
 
@@ -18,117 +18,202 @@ pub enum Tag<'a> {
     Pre(Vec<Tag<'a>>),
     Quote((&'a str, &'a str, Vec<Tag<'a>>)),
     Spoiler((Option<&'a str>, Vec<Tag<'a>>)),
+    Color(Vec<Tag<'a>>),
+    Size(Vec<Tag<'a>>),
+    Font(Vec<Tag<'a>>),
+    Align(Vec<Tag<'a>>),
+    Background(Vec<Tag<'a>>),
+    Anchor(Vec<Tag<'a>>),
+    List((bool, Vec<Tag<'a>>)),
+    ListItem(Vec<Tag<'a>>),
+    Table(Vec<Tag<'a>>),
+    TableRow(Vec<Tag<'a>>),
+    TableCell((bool, Vec<Tag<'a>>)),
+    Box(Vec<Tag<'a>>),
+    Image(&'a str),
+    Rule,
+}
+
+impl<'a> Tag<'a> {
+    /// The tags nested inside this one, if any.
+    pub fn children(&self) -> &[Tag<'a>] {
+        match self {
+            Tag::Bold(children) | Tag::Italic(children) | Tag::Underline(children) | Tag::Strike(children)
+            | Tag::Sub(children) | Tag::Sup(children) | Tag::Pre(children) | Tag::Color(children)
+            | Tag::Size(children) | Tag::Font(children) | Tag::Align(children) | Tag::Background(children)
+            | Tag::Anchor(children) | Tag::ListItem(children) | Tag::Table(children) | Tag::TableRow(children)
+            | Tag::Box(children) => children,
+            Tag::Proposal((_, children)) | Tag::Url((_, children)) | Tag::Spoiler((_, children))
+            | Tag::List((_, children)) | Tag::TableCell((_, children)) => children,
+            Tag::Resolution((_, _, children)) | Tag::Quote((_, _, children)) => children,
+            Tag::Text(_) | Tag::Nation(_) | Tag::Region(_) | Tag::Image(_) | Tag::Rule => &[],
+        }
+    }
 }
 
 #[derive(Parser)]
 #[grammar = "nscode.pest"]
 struct NsCodeParser;
 
-fn walk_pair(pair: Pair<'_, Rule>) -> Vec<Tag<'_>> {
-    match pair.as_rule() {
-        Rule::tree => {
-            walk_pairs(pair.into_inner())
-        }
-
-        Rule::TEXT | Rule::invalid_tag  => {
-            vec![Tag::Text(pair.as_str())]
-        }
+/// Maximum nesting depth of tags. Rendering recurses once per level, so deeper tags are kept as text.
+const MAX_NESTING_DEPTH: usize = 64;
 
-        Rule::bold_tag => vec![Tag::Bold(walk_pairs(pair.into_inner()))],
-        Rule::italic_tag => vec![Tag::Italic(walk_pairs(pair.into_inner()))],
-        Rule::underline_tag => vec![Tag::Underline(walk_pairs(pair.into_inner()))],
-        Rule::strike_tag => vec![Tag::Strike(walk_pairs(pair.into_inner()))],
-        Rule::sub_tag => vec![Tag::Sub(walk_pairs(pair.into_inner()))],
-        Rule::sup_tag => vec![Tag::Sup(walk_pairs(pair.into_inner()))],
-
-        Rule::nation_tag => {
-            let name = pair.into_inner()
-                .find(|p| p.as_rule() == Rule::NAME)
-                .map_or("", |p| p.as_str());
-            vec![Tag::Nation(name)]
-        }
-
-        Rule::region_tag => {
-            let name = pair.into_inner()
-                .find(|p| p.as_rule() == Rule::NAME)
-                .map_or("", |p| p.as_str());
-            vec![Tag::Region(name)]
-        }
+/// A tag that has been opened but not closed yet.
+struct Frame<'a> {
+    name: &'a str,
+    argument: Option<&'a str>,
+    source: &'a str,
+    children: Vec<Tag<'a>>,
+}
 
-        Rule::proposal_tag => {
-            let mut inner = pair.into_inner();
-            let id = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::PROPOSAL { Some(p.as_str()) } else { None })
-                .unwrap_or("");
-            let children = walk_pairs(inner);
-            vec![Tag::Proposal((id, children))]
-        }
+fn is_name(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '_' || c == '-')
+}
 
-        Rule::resolution_tag => {
-            let mut inner = pair.into_inner();
-            let council = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::council { Some(p.as_str()) } else { None })
-                .unwrap_or("");
-            let number = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::NUMBER { Some(p.as_str()) } else { None })
-                .unwrap_or("");
-            let children = walk_pairs(inner);
-            vec![Tag::Resolution((council, number, children))]
-        }
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())
+}
 
-        Rule::default_spoiler_tag => {
-            let children = walk_pairs(pair.into_inner());
-            vec![Tag::Spoiler((None, children))]
-        }
+fn is_proposal(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
 
-        Rule::spoiler_tag => {
-            let mut inner = pair.into_inner();
-            let title = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::TEXT_INSIDE_TAG { Some(p.as_str()) } else { None });
-            let children = walk_pairs(inner);
-            vec![Tag::Spoiler((title, children))]
-        }
+/// Builds the tag for a container tag and its children.
+/// Returns None if the name isn't a known tag or the argument isn't valid for it.
+fn build_tag<'a>(name: &str, argument: Option<&'a str>, children: Vec<Tag<'a>>) -> Option<Tag<'a>> {
+    let tag = match (name, argument) {
+        ("b", None) => Tag::Bold(children),
+        ("i", None) => Tag::Italic(children),
+        ("u", None) => Tag::Underline(children),
+        ("strike", None) => Tag::Strike(children),
+        ("sub", None) => Tag::Sub(children),
+        ("sup", None) => Tag::Sup(children),
+        ("pre", None) => Tag::Pre(children),
+        ("box", None) => Tag::Box(children),
+        ("table", None) => Tag::Table(children),
+        ("tr", None) => Tag::TableRow(children),
+        ("th", None) => Tag::TableCell((true, children)),
+        ("td", None) => Tag::TableCell((false, children)),
+        ("center" | "left" | "right", None) | ("align", Some(_)) => Tag::Align(children),
+        ("color", Some(_)) => Tag::Color(children),
+        ("size", Some(_)) => Tag::Size(children),
+        ("font", Some(_)) => Tag::Font(children),
+        ("background-block", Some(_)) => Tag::Background(children),
+        ("anchor", Some(_)) => Tag::Anchor(children),
+        ("spoiler", title) => Tag::Spoiler((title, children)),
+        ("list", None) => Tag::List((false, children)),
+        ("list", Some("1" | "a" | "A" | "i" | "I")) => Tag::List((true, children)),
+        ("url", Some(href)) if !href.is_empty() => Tag::Url((href, children)),
+        ("proposal", Some(id)) if is_proposal(id) => Tag::Proposal((id, children)),
+        ("resolution", Some(id)) => {
+            let (council, number) = id.split_once('#')?;
+            if !matches!(council, "GA" | "SC" | "UN") || !is_number(number) { return None; }
+            Tag::Resolution((council, number, children))
+        },
+        ("quote", None) => Tag::Quote(("0", "0", children)),
+        ("quote", Some(source)) => {
+            let (nation, postid) = source.split_once(';').unwrap_or((source, "0"));
+            if !is_name(nation) || !is_number(postid) { return None; }
+            Tag::Quote((nation, postid, children))
+        },
+        _ => return None
+    };
 
-        Rule::url_tag => {
-            let mut inner = pair.into_inner();
-            let href = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::TEXT_INSIDE_TAG { Some(p.as_str()) } else { None })
-                .unwrap_or("");
-            let children = walk_pairs(inner);
-            vec![Tag::Url((href, children))]
-        }
+    Some(tag)
+}
 
-        Rule::pre_tag => {
-            let children = walk_pairs(pair.into_inner());
-            vec![Tag::Pre(children)]
-        }
+/// Closes every frame above `index`. List items directly inside a list are closed normally,
+/// any other tag left open is turned back into text.
+fn unwind_to(stack: &mut Vec<Frame<'_>>, index: usize) {
+    while stack.len() > index + 1 {
+        let frame = stack.pop().unwrap();
+        let parent = stack.last_mut().unwrap();
 
-        Rule::quote_tag => {
-            let mut inner = pair.into_inner();
-            let name = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::NAME { Some(p.as_str()) } else { None })
-                .unwrap_or("");
-            let postid = inner.next()
-                .and_then(|p| if p.as_rule() == Rule::NUMBER { Some(p.as_str()) } else { None })
-                .unwrap_or("");
-            let children = walk_pairs(inner);
-            vec![Tag::Quote((name, postid, children))]
+        if frame.name == "*" && parent.name == "list" {
+            parent.children.push(Tag::ListItem(frame.children));
+        } else {
+            parent.children.push(Tag::Text(frame.source));
+            parent.children.extend(frame.children);
         }
-
-        _ => vec![],
     }
 }
 
-fn walk_pairs(pairs: Pairs<'_, Rule>) -> Vec<Tag<'_>> {
-    pairs.flat_map(walk_pair).collect()
+fn find_inner<'a>(pair: Pair<'a, Rule>, rule: Rule) -> Option<&'a str> {
+    pair.into_inner().find(|p| p.as_rule() == rule).map(|p| p.as_str())
 }
 
-pub fn parse(text: &str) -> Option<Vec<Tag<'_>>> {
-    if let Ok(pairs) = NsCodeParser::parse(Rule::tree, text) {
-        Some(walk_pairs(pairs))
-    } else {
-        None
+/// Parses NSCode into a tag tree. Never fails: opening tags without a matching closing tag
+/// (and the other way around) are kept as text, and the rest of the post is parsed as usual.
+pub fn parse(text: &str) -> Vec<Tag<'_>> {
+    let Ok(mut pairs) = NsCodeParser::parse(Rule::tree, text) else {
+        return vec![Tag::Text(text)];
+    };
+
+    let Some(tree) = pairs.next() else { return Vec::new() };
+
+    let mut stack = vec![Frame { name: "", argument: None, source: "", children: Vec::new() }];
+
+    for pair in tree.into_inner() {
+        let source = pair.as_str();
+
+        let tag = match pair.as_rule() {
+            Rule::nation_tag => Tag::Nation(find_inner(pair, Rule::NAME).unwrap_or("")),
+            Rule::region_tag => Tag::Region(find_inner(pair, Rule::NAME).unwrap_or("")),
+            Rule::img_tag => Tag::Image(find_inner(pair, Rule::URL).unwrap_or("")),
+            Rule::hr_tag => Tag::Rule,
+
+            Rule::list_item => {
+                if let Some(index) = stack.iter().rposition(|frame| frame.name == "list") {
+                    unwind_to(&mut stack, index);
+                    stack.push(Frame { name: "*", argument: None, source, children: Vec::new() });
+                    continue;
+                }
+
+                Tag::Text(source)
+            },
+
+            Rule::open_tag => {
+                let mut inner = pair.into_inner();
+                let name = inner.next().map_or("", |p| p.as_str());
+                let (argument, attributes) = match inner.next() {
+                    Some(p) if p.as_rule() == Rule::TAG_ARGUMENT => (Some(p.as_str()), false),
+                    Some(_) => (None, true),
+                    None => (None, false)
+                };
+
+                let allowed = !attributes || matches!(name, "table" | "tr" | "th" | "td");
+
+                if allowed && stack.len() <= MAX_NESTING_DEPTH && build_tag(name, argument, Vec::new()).is_some() {
+                    stack.push(Frame { name, argument, source, children: Vec::new() });
+                    continue;
+                }
+
+                Tag::Text(source)
+            },
+
+            Rule::close_tag => {
+                let name = find_inner(pair, Rule::TAG_NAME).unwrap_or("");
+
+                match stack.iter().rposition(|frame| frame.name == name) {
+                    Some(index) if index > 0 => {
+                        unwind_to(&mut stack, index);
+                        let Frame { name, argument, source, children } = stack.pop().unwrap();
+
+                        build_tag(name, argument, children).unwrap_or(Tag::Text(source))
+                    },
+                    _ => Tag::Text(source)
+                }
+            },
+
+            Rule::TEXT | Rule::stray_bracket => Tag::Text(source),
+            _ => continue
+        };
+
+        stack.last_mut().unwrap().children.push(tag);
     }
+
+    unwind_to(&mut stack, 0);
+    stack.pop().map_or(Vec::new(), |root| root.children)
 }
 
 pub fn remove_subquotes(text: &str) -> String {
@@ -148,4 +233,107 @@ pub fn remove_subquotes(text: &str) -> String {
     }
 
     result.into_iter().collect::<String>().trim().to_owned()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::render_tags;
+
+    const FRAGMENTS: &[&str] = &[
+        "[b]", "[/b]", "[i]", "[/i]", "[u]", "[/u]", "[strike]", "[/strike]", "[sub]", "[/sub]",
+        "[quote=testlandia;123]", "[quote]", "[/quote]", "[spoiler=title]", "[spoiler]", "[/spoiler]",
+        "[url=https://example.com]", "[/url]", "[nation]", "[/nation]", "[region]", "[/region]",
+        "[list]", "[list=1]", "[*]", "[/list]", "[table]", "[tr]", "[td]", "[/td]", "[/tr]", "[/table]",
+        "[img]", "[/img]", "[hr]", "[color=red]", "[/color]", "[", "]", "[/", "=", ";",
+        "text", "testlandia", " ", "\n", "é", "😀", "ß",
+    ];
+
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+    }
+
+    fn text_content(tags: &[Tag<'_>]) -> String {
+        tags.iter().map(|tag| match tag {
+            Tag::Text(text) => text.to_string(),
+            _ => panic!("expected only text tags"),
+        }).collect()
+    }
+
+    #[test]
+    fn unmatched_tags_are_kept_as_text() {
+        for text in ["[b]unclosed", "closed[/b]", "a [ b", "[quote=nation;1]no end", "]["] {
+            assert_eq!(text_content(&parse(text)), text);
+        }
+    }
+
+    #[test]
+    fn unmatched_tag_does_not_break_the_rest() {
+        let tags = parse("[i]open [b]bold[/b] [u]under[/u]");
+
+        assert!(tags.iter().any(|tag| matches!(tag, Tag::Bold(_))));
+        assert!(tags.iter().any(|tag| matches!(tag, Tag::Underline(_))));
+    }
+
+    #[test]
+    fn strike_tag_closes_on_strike() {
+        let tags = parse("[strike]a [u]b[/u] c[/strike]");
+
+        assert!(matches!(tags.as_slice(), [Tag::Strike(_)]));
+    }
+
+    #[test]
+    fn crossed_tags_keep_the_outer_tag() {
+        let tags = parse("[b]a[i]b[/b]c[/i]");
+
+        assert!(matches!(tags.as_slice(), [Tag::Bold(_), Tag::Text("c"), Tag::Text("[/i]")]));
+    }
+
+    #[test]
+    fn list_items_close_implicitly() {
+        let tags = parse("[list][*]one[*]two[/list]");
+
+        let [Tag::List((false, items))] = tags.as_slice() else { panic!("expected a list") };
+        assert!(matches!(items.as_slice(), [Tag::ListItem(_), Tag::ListItem(_)]));
+    }
+
+    #[test]
+    fn deeply_nested_tags_do_not_overflow() {
+        let text = format!("{}x{}", "[b]".repeat(10000), "[/b]".repeat(10000));
+
+        render_tags(parse(&text), 4096);
+    }
+
+    #[test]
+    fn many_unclosed_tags_are_kept_as_text() {
+        let text = "[b][i][u][sub][sup][strike]".repeat(100);
+
+        assert_eq!(text_content(&parse(&text)), text);
+    }
+
+    // Deterministic stress test: a fixed seed strings together tag fragments, so failures are reproducible.
+    #[test]
+    fn parse_never_fails_on_mixed_fragments() {
+        let mut rng = XorShift(0x2545F4914F6CDD1D);
+
+        for _ in 0..2000 {
+            let length = rng.next() % 64;
+            let text: String = (0..length)
+                .map(|_| FRAGMENTS[rng.next() % FRAGMENTS.len()])
+                .collect();
+
+            let tags = parse(&text);
+            assert!(text.is_empty() || !tags.is_empty(), "no tags for {text:?}");
+
+            render_tags(tags, 4096);
+            render_tags(parse(&text), 64);
+        }
+    }
+}
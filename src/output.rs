@@ -7,10 +7,15 @@ use serenity::all::{CreateButton, Http};
 use caramel::ns::UserAgent;
 use caramel::types::akari::Event;
 
-use crate::config::OutputConfig;
-use crate::rmb::output_rmb_post;
-use crate::webhook::{build_event_embed, send_embed_to_webhook};
-use crate::utils::{chamber_link, display_chamber, display_nation, display_proposal_name, display_proposal_url, display_region};
+use crate::cache::NSCache;
+use crate::events::{EndorsementCount, EventData, Residency};
+use crate::config::{OutputConfig, OutputStyle};
+use crate::rmb::{output_rmb_post, update_rmb_post};
+use crate::webhook::{build_compact_message, build_event_embed, send_embed_to_webhook, send_message_to_webhook};
+use crate::utils::{
+    add_generated_by, chamber_link, display_chamber, display_nation, 
+    display_proposal_name, display_proposal_url, display_region, escape_markdown, suppress_links
+};
 
 pub enum Field {
     Actor,
@@ -185,6 +190,275 @@ fn process_wa_discard(event: &Event) -> Option<String> {
     ))
 }
 
+fn process_wa_approve(event: &Event) -> Option<String> {
+    let delegate = event.actor.as_ref()?;
+    let chamber = event.data.first()?;
+    let proposal = event.data.get(1)?;
+
+    Some(format!("{} approved the {} proposal {}", 
+        display_nation(delegate, true),
+        display_chamber(chamber, false),
+        display_proposal_name(proposal)
+    ))
+}
+
+fn process_wa_tally(event: &Event) -> Option<String> {
+    let chamber = event.data.first()?;
+    let proposal = event.data.get(1)?;
+    let votes_for = event.data.get(2)?;
+    let votes_against = event.data.get(3)?;
+
+    Some(format!("The {} resolution {} has {} votes FOR and {} votes AGAINST", 
+        display_chamber(chamber, false),
+        display_proposal_name(proposal),
+        votes_for,
+        votes_against
+    ))
+}
+
+fn process_wa_lead(event: &Event) -> Option<String> {
+    let chamber = event.data.first()?;
+    let proposal = event.data.get(1)?;
+    let votes_for: u64 = event.data.get(2)?.parse().ok()?;
+    let votes_against: u64 = event.data.get(3)?.parse().ok()?;
+
+    Some(format!("The {} resolution {} is now {}, with {} votes FOR to {} votes AGAINST", 
+        display_chamber(chamber, false),
+        display_proposal_name(proposal),
+        if votes_for > votes_against { "**passing**" } else { "**failing**" },
+        votes_for,
+        votes_against
+    ))
+}
+
+fn process_wa_vote(event: &Event) -> Option<String> {
+    let delegate = event.actor.as_ref()?;
+    let chamber = event.data.first()?;
+    let proposal = event.data.get(1)?;
+
+    let action = match event.data.get(4)?.as_str() {
+        "for" => "voted **FOR**",
+        "against" => "voted **AGAINST**",
+        _ => "withdrew its vote on"
+    };
+
+    Some(format!("{} {} the {} resolution {}", 
+        display_nation(delegate, true),
+        action,
+        display_chamber(chamber, false),
+        display_proposal_name(proposal)
+    ))
+}
+
+fn process_sc_target(event: &Event) -> Option<String> {
+    let proposal = event.data.first()?;
+    let category = event.data.get(1)?;
+    let stage = event.data.get(2)?;
+
+    let target = match (&event.receptor, &event.destination) {
+        (Some(nation), _) => display_nation(nation, true),
+        (None, Some(region)) => display_region(region, true),
+        _ => return None
+    };
+
+    let status = if stage == "at vote" { "is now at vote" } else { "was submitted" };
+
+    Some(format!("The {} {} proposal {}, targeting {}, {}", 
+        display_chamber("Security Council", false), category, display_proposal_name(proposal), target, status
+    ))
+}
+
+fn process_rmb_suppress(event: &Event) -> Option<String> {
+    let origin = event.origin.as_ref()?;
+    let postid = event.data.first()?;
+
+    let post = format!("[A post](https://www.nationstates.net/page=display_region_rmb/region={}?postid={}#p{}) on {}'s RMB", 
+        origin, postid, postid, display_region(origin, true)
+    );
+
+    match (event.category.as_str(), event.actor.as_ref()) {
+        ("rmbsuppress", Some(actor)) => Some(format!("{post} was suppressed by {}", display_nation(actor, true))),
+        ("rmbsuppress", None) => Some(format!("{post} was suppressed")),
+        _ => Some(format!("{post} was deleted")),
+    }
+}
+
+fn process_mentioned(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+    let postid = event.data.first()?;
+
+    let target = match &event.receptor {
+        Some(nation) => display_nation(nation, true),
+        None => display_region(event.destination.as_ref()?, true)
+    };
+
+    Some(format!("{} mentioned {} in [a post](https://www.nationstates.net/page=display_region_rmb/region={}?postid={}#p{}) on {}'s RMB", 
+        display_nation(actor, true), target, origin, postid, postid, display_region(origin, false)
+    ))
+}
+
+fn process_delegate_endo(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+
+    if event.category == "endo" {
+        Some(format!("{} endorsed the delegate, {}", display_nation(actor, true), display_nation(receptor, true)))
+    } else {
+        Some(format!("{} withdrew its endorsement from the delegate, {}", display_nation(actor, true), display_nation(receptor, true)))
+    }
+}
+
+/// " by Nation" when the happening says who did it, or nothing otherwise.
+fn by_actor(event: &Event) -> String {
+    event.actor.as_ref().map_or(String::new(), |actor| format!(" by {}", display_nation(actor, true)))
+}
+
+fn process_governor(event: &Event) -> Option<String> {
+    let origin = event.origin.as_ref()?;
+
+    match event.category.as_str() {
+        "rgcte" => Some(format!("The Governor of {} ceased to exist", display_region(origin, true))),
+        "govabd" => Some(format!("{} resigned as Governor of {}", 
+            display_nation(event.actor.as_ref()?, true), display_region(origin, true)
+        )),
+        "fngovrem" => Some(format!("{} was removed as Governor of {}", 
+            display_nation(event.actor.as_ref()?, true), display_region(origin, true)
+        )),
+        _ => Some(format!("{} became Governor of {}", 
+            display_nation(event.actor.as_ref()?, true), display_region(origin, true)
+        )),
+    }
+}
+
+fn process_ro_appoint(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+    let office = escape_markdown(event.data.first()?);
+
+    match event.data.get(1).filter(|authority| !authority.is_empty()).map(|authority| escape_markdown(authority)) {
+        Some(authority) => Some(format!("{} appointed {} as {} of {} with authority over {}", 
+            display_nation(actor, true), display_nation(receptor, true), office, display_region(origin, true), authority
+        )),
+        None => Some(format!("{} appointed {} as {} of {}", 
+            display_nation(actor, true), display_nation(receptor, true), office, display_region(origin, true)
+        )),
+    }
+}
+
+fn process_ro_dismiss(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+    let office = escape_markdown(event.data.first()?);
+
+    Some(format!("{} dismissed {} as {} of {}", 
+        display_nation(actor, true), display_nation(receptor, true), office, display_region(origin, true)
+    ))
+}
+
+fn process_ro_rename(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+    let old_office = escape_markdown(event.data.first()?);
+    let new_office = escape_markdown(event.data.get(1)?);
+
+    Some(format!("{} renamed the office held by {} in {} from {} to {}", 
+        display_nation(actor, true), display_nation(receptor, true), display_region(origin, true), old_office, new_office
+    ))
+}
+
+fn process_ro_authority(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+
+    let mut changes = Vec::new();
+    if let Some(granted) = event.data.first().filter(|granted| !granted.is_empty()) {
+        changes.push(format!("granted {}", escape_markdown(granted)));
+    }
+    if let Some(removed) = event.data.get(1).filter(|removed| !removed.is_empty()) {
+        changes.push(format!("removed {}", escape_markdown(removed)));
+    }
+
+    if changes.is_empty() {
+        Some(format!("{} changed the authority of {} in {}", 
+            display_nation(actor, true), display_nation(receptor, true), display_region(origin, true)
+        ))
+    } else {
+        Some(format!("{} changed the authority of {} in {} ({})", 
+            display_nation(actor, true), display_nation(receptor, true), display_region(origin, true), changes.join(", ")
+        ))
+    }
+}
+
+fn process_password(event: &Event) -> Option<String> {
+    let origin = event.origin.as_ref()?;
+
+    if event.category == "rpwadd" {
+        Some(format!("A password was set on {}{}", display_region(origin, true), by_actor(event)))
+    } else {
+        Some(format!("The password was removed from {}{}", display_region(origin, true), by_actor(event)))
+    }
+}
+
+fn process_embassy(event: &Event) -> Option<String> {
+    let origin = display_region(event.origin.as_ref()?, true);
+    let destination = display_region(event.destination.as_ref()?, false);
+
+    match event.category.as_str() {
+        "embreq" => Some(format!("Embassies between {origin} and {destination} were proposed{}", by_actor(event))),
+        "embcon" => Some(format!("Embassy construction began between {origin} and {destination}")),
+        "embcancel" => Some(format!("The closure of embassies between {origin} and {destination} was cancelled{}", by_actor(event))),
+        "embclose" => Some(format!("Embassies between {origin} and {destination} were ordered closed{}", by_actor(event))),
+        "embrej" => Some(format!("A request for embassies between {origin} and {destination} was rejected{}", by_actor(event))),
+        _ => Some(format!("Embassy construction between {origin} and {destination} was aborted{}", by_actor(event))),
+    }
+}
+
+fn process_wfe(event: &Event) -> Option<String> {
+    Some(format!("The World Factbook Entry of {} was updated{}", display_region(event.origin.as_ref()?, true), by_actor(event)))
+}
+
+fn process_ban(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+
+    if event.category == "ban" {
+        Some(format!("{} banned {} from {}", display_nation(actor, true), display_nation(receptor, true), display_region(origin, true)))
+    } else {
+        Some(format!("{} removed {} from the ban list of {}", display_nation(actor, true), display_nation(receptor, true), display_region(origin, true)))
+    }
+}
+
+fn process_eject(event: &Event) -> Option<String> {
+    let actor = event.actor.as_ref()?;
+    let receptor = event.receptor.as_ref()?;
+    let origin = event.origin.as_ref()?;
+
+    let action = if event.category == "banject" { "ejected and banned" } else { "ejected" };
+
+    match &event.destination {
+        Some(destination) => Some(format!("{} {action} {} from {} to {}", 
+            display_nation(actor, true), display_nation(receptor, true), display_region(origin, true), display_region(destination, false)
+        )),
+        None => Some(format!("{} {action} {} from {}", 
+            display_nation(actor, true), display_nation(receptor, true), display_region(origin, true)
+        )),
+    }
+}
+
+fn process_flag(event: &Event) -> Option<String> {
+    Some(format!("The flag of {} was changed{}", display_region(event.origin.as_ref()?, true), by_actor(event)))
+}
+
+fn process_banner(event: &Event) -> Option<String> {
+    Some(format!("The banner of {} was changed{}", display_region(event.origin.as_ref()?, true), by_actor(event)))
+}
+
 fn create_output_map() -> OutputMap {
     let mut line_map = HashMap::new();
 
@@ -216,7 +490,13 @@ fn create_output_map() -> OutputMap {
         Actor, Text(" applied to join the World Assembly in "), HighlightOrigin
     ].into());
     line_map.insert("wakick", vec![
-        Receptor, Text(" was ejected from the World Assembly for rule violations in "), HighlightOrigin
+        Actor, Text(" was ejected from the World Assembly for rule violations in "), HighlightOrigin
+    ].into());
+    line_map.insert("endo", vec![
+        Actor, Text(" endorsed "), Receptor
+    ].into());
+    line_map.insert("unendo", vec![
+        Actor, Text(" withdrew its endorsement from "), Receptor
     ].into());
     line_map.insert("update", vec![
         HighlightOrigin, Text(" updated")
@@ -226,39 +506,204 @@ fn create_output_map() -> OutputMap {
     ].into());
     line_map.insert("found", Processor::init(vec![], process_found));
     line_map.insert("delegate", Processor::init(vec![], process_delegate));
+    line_map.insert("rmb-suppress", Processor::init(vec![], process_rmb_suppress));
+    line_map.insert("mentioned", Processor::init(vec![], process_mentioned));
+    line_map.insert("delegate-endo-change", Processor::init(vec![], process_delegate_endo));
+    line_map.insert("governor", Processor::init(vec![], process_governor));
+    line_map.insert("ro-appoint", Processor::init(vec![], process_ro_appoint));
+    line_map.insert("ro-dismiss", Processor::init(vec![], process_ro_dismiss));
+    line_map.insert("ro-rename", Processor::init(vec![], process_ro_rename));
+    line_map.insert("ro-authority", Processor::init(vec![], process_ro_authority));
+    line_map.insert("password", Processor::init(vec![], process_password));
+    line_map.insert("embassy-request", Processor::init(vec![], process_embassy));
+    line_map.insert("embassy-open", Processor::init(vec![], process_embassy));
+    line_map.insert("embassy-close", Processor::init(vec![], process_embassy));
+    line_map.insert("wfe", Processor::init(vec![], process_wfe));
+    line_map.insert("ban", Processor::init(vec![], process_ban));
+    line_map.insert("unban", Processor::init(vec![], process_ban));
+    line_map.insert("eject", Processor::init(vec![], process_eject));
+    line_map.insert("banject", Processor::init(vec![], process_eject));
+    line_map.insert("flag", Processor::init(vec![], process_flag));
+    line_map.insert("banner", Processor::init(vec![], process_banner));
     line_map.insert("wa-floor", Processor::init(vec![], process_wa_floor));
     line_map.insert("wa-submit", Processor::init(vec![], process_wa_submit));
     line_map.insert("wa-pass", Processor::init(vec![], process_wa_pass));
     line_map.insert("wa-fail", Processor::init(vec![], process_wa_fail));
     line_map.insert("wa-discard", Processor::init(vec![], process_wa_discard));
+    line_map.insert("wa-approve", Processor::init(vec![], process_wa_approve));
+    line_map.insert("sc-target", Processor::init(vec![], process_sc_target));
+    line_map.insert("wa-tally", Processor::init(vec![], process_wa_tally));
+    line_map.insert("wa-lead", Processor::init(vec![], process_wa_lead));
+    line_map.insert("wa-vote", Processor::init(vec![], process_wa_vote));
+    line_map.insert("ga-floor", Processor::init(vec![], process_wa_floor));
+    line_map.insert("ga-submit", Processor::init(vec![], process_wa_submit));
+    line_map.insert("ga-pass", Processor::init(vec![], process_wa_pass));
+    line_map.insert("ga-fail", Processor::init(vec![], process_wa_fail));
+    line_map.insert("ga-discard", Processor::init(vec![], process_wa_discard));
+    line_map.insert("ga-approve", Processor::init(vec![], process_wa_approve));
+    line_map.insert("ga-tally", Processor::init(vec![], process_wa_tally));
+    line_map.insert("ga-lead", Processor::init(vec![], process_wa_lead));
+    line_map.insert("ga-vote", Processor::init(vec![], process_wa_vote));
+    line_map.insert("sc-floor", Processor::init(vec![], process_wa_floor));
+    line_map.insert("sc-submit", Processor::init(vec![], process_wa_submit));
+    line_map.insert("sc-pass", Processor::init(vec![], process_wa_pass));
+    line_map.insert("sc-fail", Processor::init(vec![], process_wa_fail));
+    line_map.insert("sc-discard", Processor::init(vec![], process_wa_discard));
+    line_map.insert("sc-approve", Processor::init(vec![], process_wa_approve));
+    line_map.insert("sc-tally", Processor::init(vec![], process_wa_tally));
+    line_map.insert("sc-lead", Processor::init(vec![], process_wa_lead));
+    line_map.insert("sc-vote", Processor::init(vec![], process_wa_vote));
 
     line_map
 }
 
 static OUTPUT_MAP: LazyLock<OutputMap> = LazyLock::new(|| create_output_map());
 
+/// Fills in `{actor}`, `{receptor}`, `{origin}` and `{destination}` placeholders in a button URL template.
+/// Returns None if the template uses a field the event doesn't have.
+fn expand_url_template(template: &str, event: &Event) -> Option<String> {
+    fill_template(template, &[
+        ("actor", event.actor.as_deref()),
+        ("receptor", event.receptor.as_deref()),
+        ("origin", event.origin.as_deref()),
+        ("destination", event.destination.as_deref()),
+    ])
+}
+
+/// Replaces `{name}` placeholders with the matching field. Placeholders that aren't fields are kept as they are.
+fn fill_template(template: &str, fields: &[(&str, Option<&str>)]) -> Option<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else { break };
+        let end = start + length;
+
+        result.push_str(&rest[..start]);
+        result.push_str(match fields.iter().find(|(name, _)| *name == &rest[start+1..end]) {
+            Some((_, value)) => (*value)?,
+            None => &rest[start..=end],
+        });
+
+        rest = &rest[end+1..];
+    }
+
+    result.push_str(rest);
+    Some(result)
+}
+
+pub fn build_custom_buttons(
+    output_config: &OutputConfig,
+    event: &Event,
+    user_agent: &UserAgent
+) -> Vec<CreateButton> {
+    output_config.buttons.iter().filter_map(|button| {
+        let Some(url) = expand_url_template(&button.url, event) else {
+            warn!("Event {} is missing fields for button '{}'", event.category, button.label);
+            return None;
+        };
+
+        Some(CreateButton::new_link(add_generated_by(&url, &user_agent.web())).label(&button.label))
+    }).collect()
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (amount, unit) = match seconds {
+        0..3600 => ((seconds / 60).max(1), "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+
+    format!("{amount} {unit}{}", if amount == 1 { "" } else { "s" })
+}
+
+/// Describes how long a departing nation had been resident, or that an arriving nation is returning.
+fn residency_note(residency: &Residency, time: u64) -> String {
+    let mut notes = Vec::new();
+
+    if residency.returning {
+        notes.push("returning resident".to_string());
+    }
+
+    if let Some(since) = residency.since {
+        notes.push(format!("resident for {}", format_duration(time.saturating_sub(since))));
+    }
+
+    if let Some(influence) = &residency.influence {
+        notes.push(format!("influence: {influence}"));
+    }
+
+    if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) }
+}
+
+/// Describes a delegate's endorsement count, and how far it is below its peak.
+fn endorsement_note(endorsements: &EndorsementCount) -> String {
+    let plural = if endorsements.count == 1 { "" } else { "s" };
+
+    if endorsements.count < endorsements.peak {
+        format!(" (now {} endorsement{plural}, down {} from {})", 
+            endorsements.count, endorsements.peak - endorsements.count, endorsements.peak
+        )
+    } else {
+        format!(" (now {} endorsement{plural})", endorsements.count)
+    }
+}
+
+/// With a `threshold`, endorsement changes are only sent when the delegate loses another `threshold` endorsements below its peak.
+async fn below_threshold(output_config: &OutputConfig, data: &EventData, cache: &NSCache) -> bool {
+    let (Some(threshold), Some(endorsements), Some(region)) = (output_config.threshold, &data.endorsements, &data.region) else {
+        return false 
+    };
+    let drop = endorsements.peak.saturating_sub(endorsements.count);
+
+    !cache.reached_endorsement_level(region, &output_config.hook_url, threshold, drop).await
+}
+
 pub async fn output_event(
     http: &Http,
-    category: &str,
+    data: &EventData,
     output_config: &OutputConfig,
     event: &Event,
+    cache: &NSCache,
     user_agent: &UserAgent
 ) -> Result<(), Box<dyn std::error::Error>> {  
+    let category = data.name;
+
     if category == "rmb" {
-        output_rmb_post(http, output_config, event, user_agent).await?;
+        output_rmb_post(http, output_config, event, cache, user_agent).await?;
 
         return Ok(());
     } 
 
+    if category == "delegate-endo-change" && below_threshold(output_config, data, cache).await {
+        return Ok(());
+    }
+
     if let Some(processor) = OUTPUT_MAP.get(category) {
-        let Some(description) = processor.process(event) else {
+        let Some(mut description) = processor.process(event) else {
             warn!("Event {} is missing fields: {:?}", event.category, event);
             return Ok(());
         };
 
+        if let Some(residency) = &data.residency {
+            description.push_str(&residency_note(residency, event.time));
+        }
+
+        if let Some(endorsements) = &data.endorsements {
+            description.push_str(&endorsement_note(endorsements));
+        }
+
+        if data.wa {
+            description.push_str(" **(WA nation)**");
+        }
+
+        if category == "rmb-suppress" && update_rmb_post(http, output_config, event, cache, &description).await? {
+            return Ok(());
+        }
+
         let mut buttons: Vec<CreateButton> = Vec::new();
         
-        if category == "wajoin" || category == "admit" {
+        if output_config.default_buttons && (category == "wajoin" || category == "admit") {
             buttons.push(
                 CreateButton::new_link(
                     format!("https://www.nationstates.net/nation={}?generated_by={}#endorse", 
@@ -268,7 +713,7 @@ pub async fn output_event(
             );
         }
 
-        if category == "wa-floor" {
+        if output_config.default_buttons && matches!(category, "wa-floor" | "ga-floor" | "sc-floor") {
             buttons.push(
                 CreateButton::new_link(
                     format!("{}?generated_by={}", 
@@ -278,6 +723,24 @@ pub async fn output_event(
             );
         }
 
+        buttons.extend(build_custom_buttons(output_config, event, user_agent));
+
+        if output_config.style == OutputStyle::Compact {
+            let content = build_compact_message(
+                &suppress_links(&description), event.time, output_config.timestamp.as_deref()
+            );
+
+            send_message_to_webhook(
+                http,
+                &output_config.hook,
+                output_config.mentions.clone(),
+                &content,
+                buttons
+            ).await?;
+
+            return Ok(());
+        }
+
         let embed = build_event_embed(
             output_config.color, &description, event.time, None
         )?;
@@ -292,4 +755,54 @@ pub async fn output_event(
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: &[(&str, Option<&str>)] = &[("actor", Some("testlandia")), ("origin", Some("lazarus")), ("receptor", None)];
+
+    #[test]
+    fn templates_are_filled_in() {
+        assert_eq!(
+            fill_template("https://www.nationstates.net/nation={actor}/detail=trend?region={origin}", FIELDS).as_deref(),
+            Some("https://www.nationstates.net/nation=testlandia/detail=trend?region=lazarus")
+        );
+    }
+
+    #[test]
+    fn templates_with_missing_fields_are_skipped() {
+        assert_eq!(fill_template("https://www.nationstates.net/nation={receptor}", FIELDS), None);
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        assert_eq!(fill_template("https://example.com/{page}/{actor}", FIELDS).as_deref(), Some("https://example.com/{page}/testlandia"));
+        assert_eq!(fill_template("https://example.com/{actor", FIELDS).as_deref(), Some("https://example.com/{actor"));
+    }
+
+    #[test]
+    fn durations_use_the_largest_whole_unit() {
+        assert_eq!(format_duration(0), "1 minute");
+        assert_eq!(format_duration(150), "2 minutes");
+        assert_eq!(format_duration(3600), "1 hour");
+        assert_eq!(format_duration(86399), "23 hours");
+        assert_eq!(format_duration(86400 * 40), "40 days");
+    }
+
+    #[test]
+    fn residency_notes_list_what_is_known() {
+        assert_eq!(residency_note(&Residency::default(), 1000), "");
+        assert_eq!(residency_note(&Residency { returning: true, ..Default::default() }, 1000), " (returning resident)");
+
+        let leaving = Residency { since: Some(1000), returning: false, influence: Some("Nobody".to_string()) };
+        assert_eq!(residency_note(&leaving, 1000 + 2 * 86400), " (resident for 2 days, influence: Nobody)");
+    }
+
+    #[test]
+    fn arrivals_in_the_future_count_as_a_minute() {
+        let residency = Residency { since: Some(2000), ..Default::default() };
+        assert_eq!(residency_note(&residency, 1000), " (resident for 1 minute)");
+    }
+}
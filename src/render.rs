@@ -1,163 +1,639 @@
 use caramel::ns::format::prettify_name;
 
-use crate::utils::{display_nation, display_region};
+use crate::utils::{display_nation, display_region, escape_markdown, is_discord_invite};
 use crate::nscode::Tag;
 
-fn add_until_limit(vec: &mut Vec<u8>, bytes: &[u8], limit: &mut usize) {
-    if bytes.len() > *limit {
-        vec.extend_from_slice(&bytes[0..*limit]);
-        *limit = 0;
-    } else {
-        vec.extend_from_slice(bytes);
-        *limit -= bytes.len();
-    }
+const TRUNCATION_MARKER: &str = "… (truncated, view full post)";
+const MAX_QUOTE_LENGTH: usize = 512;
+
+/// Text rendered within a budget of characters (not bytes, since Discord counts characters).
+/// Text is only ever cut at a character boundary, and Markdown wrappers are only added around text in full.
+struct Rendered {
+    text: String,
+    remaining: usize,
+    truncated: bool,
+    code: bool,
 }
 
-fn add_if_within_limit(vec: &mut Vec<u8>, bytes: &[u8], limit: &mut usize) -> bool {
-    if bytes.len() > *limit {
-        false
-    } else {
-        vec.extend_from_slice(bytes);
-        *limit -= bytes.len();
-        true
-    }
+fn char_count(text: &str) -> usize {
+    text.chars().count()
 }
 
-fn wrap_if_within_limit(vec: &mut Vec<u8>, bytes: &[u8], start: &[u8], end: &[u8], limit: &mut usize) {
-    if bytes.len() <= *limit {
-        if bytes.len() + start.len() + end.len() > *limit {
-            vec.extend_from_slice(bytes);
-            *limit -= bytes.len();
+impl Rendered {
+    fn new(limit: usize, code: bool) -> Self {
+        Self { text: String::new(), remaining: limit, truncated: false, code }
+    }
+
+    fn render_nested(&self, tags: &[Tag<'_>], limit: usize) -> Rendered {
+        render_within(tags, limit, self.code)
+    }
+
+    fn is_at_line_start(&self) -> bool {
+        self.text.is_empty() || self.text.ends_with('\n')
+    }
+
+    fn add_until_limit(&mut self, text: &str) {
+        let length = char_count(text);
+
+        if length > self.remaining {
+            let end = text.char_indices().nth(self.remaining).map_or(text.len(), |(index, _)| index);
+            self.text.push_str(&text[..end]);
+            self.remaining = 0;
+            self.truncated = true;
         } else {
-            vec.extend_from_slice(start);
-            *limit -= start.len();
-            vec.extend_from_slice(bytes);
-            *limit -= bytes.len();
-            vec.extend_from_slice(end);
-            *limit -= end.len();
+            self.text.push_str(text);
+            self.remaining -= length;
         }
     }
-}
 
-fn wrap_lines(vec: &mut Vec<u8>, bytes: &[u8], start: &[u8], end: &[u8], limit: &mut usize) {
-    for slice in bytes.split_inclusive(|&v| v == b'\n') {
-        if slice.ends_with(&[b'\n']) {
-            wrap_if_within_limit(
-                vec, &slice[0..slice.len()-1], start, end, limit
-            );
-            add_if_within_limit(vec, &[b'\n'], limit);
+    /// Like `add_until_limit`, but never cuts between an escaping backslash and the character after it.
+    fn add_escaped_until_limit(&mut self, text: &str) {
+        let start = self.text.len();
+        self.add_until_limit(text);
+
+        let added = &self.text[start..];
+        let backslashes = added.len() - added.trim_end_matches('\\').len();
+
+        if self.truncated && added.len() < text.len() && backslashes % 2 == 1 {
+            self.text.pop();
+            self.remaining += 1;
+        }
+    }
+
+    fn add_if_within_limit(&mut self, text: &str) -> bool {
+        let length = char_count(text);
+
+        if length > self.remaining {
+            self.truncated = true;
+            false
         } else {
-            wrap_if_within_limit(
-                vec, slice, start, end, limit
-            );
+            self.text.push_str(text);
+            self.remaining -= length;
+            true
+        }
+    }
+
+    fn add_newline_if_needed(&mut self) {
+        if !self.is_at_line_start() {
+            self.add_if_within_limit("\n");
         }
     }
+
+    /// Adds text rendered separately, keeping track of whether it was truncated.
+    fn add_rendered(&mut self, inner: &Rendered, text: &str) {
+        self.truncated |= inner.truncated;
+        self.add_until_limit(text);
+    }
+
+    fn wrap_if_within_limit(&mut self, text: &str, start: &str, end: &str) {
+        let length = char_count(text);
+
+        if length > self.remaining {
+            self.truncated = true;
+        } else if text.trim().is_empty() || length + char_count(start) + char_count(end) > self.remaining {
+            self.add_if_within_limit(text);
+        } else {
+            self.add_if_within_limit(start);
+            self.add_if_within_limit(text);
+            self.add_if_within_limit(end);
+        }
+    }
+
+    /// Wraps each line of a separately rendered text, since Discord Markdown spans don't cross lines.
+    fn wrap_lines(&mut self, inner: &Rendered, text: &str, start: &str, end: &str) {
+        self.truncated |= inner.truncated;
+
+        // Markdown isn't rendered inside code spans, so formatting is left out there
+        if self.code {
+            self.add_until_limit(text);
+            return;
+        }
+
+        for line in text.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(line) => {
+                    self.wrap_if_within_limit(line, start, end);
+                    self.add_if_within_limit("\n");
+                },
+                None => self.wrap_if_within_limit(line, start, end)
+            }
+        }
+    }
+}
+
+fn superscript(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '⁰', '1' => '¹', '2' => '²', '3' => '³', '4' => '⁴',
+        '5' => '⁵', '6' => '⁶', '7' => '⁷', '8' => '⁸', '9' => '⁹',
+        '+' => '⁺', '-' => '⁻', '=' => '⁼', '(' => '⁽', ')' => '⁾',
+        'a' => 'ᵃ', 'b' => 'ᵇ', 'c' => 'ᶜ', 'd' => 'ᵈ', 'e' => 'ᵉ', 'f' => 'ᶠ', 'g' => 'ᵍ',
+        'h' => 'ʰ', 'i' => 'ⁱ', 'j' => 'ʲ', 'k' => 'ᵏ', 'l' => 'ˡ', 'm' => 'ᵐ', 'n' => 'ⁿ',
+        'o' => 'ᵒ', 'p' => 'ᵖ', 'r' => 'ʳ', 's' => 'ˢ', 't' => 'ᵗ', 'u' => 'ᵘ', 'v' => 'ᵛ',
+        'w' => 'ʷ', 'x' => 'ˣ', 'y' => 'ʸ', 'z' => 'ᶻ', ' ' => ' ',
+        _ => return None
+    })
+}
+
+fn subscript(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '₀', '1' => '₁', '2' => '₂', '3' => '₃', '4' => '₄',
+        '5' => '₅', '6' => '₆', '7' => '₇', '8' => '₈', '9' => '₉',
+        '+' => '₊', '-' => '₋', '=' => '₌', '(' => '₍', ')' => '₎',
+        'a' => 'ₐ', 'e' => 'ₑ', 'h' => 'ₕ', 'i' => 'ᵢ', 'j' => 'ⱼ', 'k' => 'ₖ', 'l' => 'ₗ',
+        'm' => 'ₘ', 'n' => 'ₙ', 'o' => 'ₒ', 'p' => 'ₚ', 'r' => 'ᵣ', 's' => 'ₛ', 't' => 'ₜ',
+        'u' => 'ᵤ', 'v' => 'ᵥ', 'x' => 'ₓ', ' ' => ' ',
+        _ => return None
+    })
+}
+
+/// Renders sub/superscript text with the matching Unicode characters if all of them exist.
+/// Otherwise falls back to Discord's `-#` small text when on its own line, or plain text.
+fn render_small_text(output: &mut Rendered, tags: &[Tag<'_>], map: fn(char) -> Option<char>, ends_line: bool) {
+    let inner = output.render_nested(tags, output.remaining);
+
+    if let Some(mapped) = inner.text.chars().map(map).collect::<Option<String>>()
+    && output.add_if_within_limit(&mapped) {
+        output.truncated |= inner.truncated;
+        return;
+    }
+
+    if output.is_at_line_start() && ends_line && !inner.text.contains('\n')
+    && output.add_if_within_limit(&format!("-# {}", inner.text)) {
+        output.truncated |= inner.truncated;
+        return;
+    }
+
+    output.add_rendered(&inner, &inner.text);
+}
+
+/// Makes a user-provided URL safe to use as a Markdown link target.
+/// Relative NationStates links are made absolute, Discord invites and anything that isn't a web link are rejected.
+fn sanitize_url(url: &str) -> Option<String> {
+    let url = url.trim();
+
+    if is_discord_invite(url) {
+        return None;
+    }
+
+    let absolute = if url.starts_with("https://") || url.starts_with("http://") {
+        url.to_string()
+    } else if url.starts_with('/') {
+        format!("https://www.nationstates.net{url}")
+    } else {
+        return None;
+    };
+
+    Some(absolute.replace(' ', "%20").replace('(', "%28").replace(')', "%29").replace('<', "%3C").replace('>', "%3E"))
 }
 
-pub fn render_as_bytes(tags: Vec<Tag<'_>>, mut limit: usize) -> Vec<u8> {
-    let mut chars: Vec<u8> = Vec::new();
+fn is_image_url(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase();
+    [".png", ".jpg", ".jpeg", ".gif", ".webp"].iter().any(|extension| path.ends_with(extension))
+}
+
+/// Finds the first image in a post, from an `[img]` tag or a bare link to an image file.
+/// Images inside quotes and spoilers are skipped, since they aren't part of the post or are hidden.
+pub fn find_image(tags: &[Tag<'_>]) -> Option<String> {
+    tags.iter().find_map(|tag| match tag {
+        Tag::Image(url) => sanitize_url(url),
+        Tag::Text(text) => text.split_whitespace()
+            .filter(|word| word.starts_with("https://") || word.starts_with("http://"))
+            .find(|word| is_image_url(word))
+            .and_then(sanitize_url),
+        Tag::Quote(_) | Tag::Spoiler(_) => None,
+        tag => find_image(tag.children()),
+    })
+}
 
-    if limit == 0 { return chars; }
+fn is_whitespace(tag: &Tag<'_>) -> bool {
+    matches!(tag, Tag::Text(text) if text.trim().is_empty())
+}
+
+fn render_list(output: &mut Rendered, tags: &[Tag<'_>], ordered: bool) {
+    let mut index = 1;
 
     for tag in tags {
         match tag {
+            Tag::ListItem(inner_tags) => {
+                output.add_newline_if_needed();
+
+                let bullet = if ordered { format!("{index}. ") } else { "- ".to_string() };
+                let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(char_count(&bullet)));
+
+                if !output.add_if_within_limit(&bullet) { return; }
+                output.add_rendered(&inner, inner.text.trim());
+                index += 1;
+            },
+            tag if is_whitespace(tag) => {},
+            tag => {
+                let inner = output.render_nested(std::slice::from_ref(tag), output.remaining);
+                output.add_rendered(&inner, &inner.text);
+            }
+        }
+    }
+
+    output.add_newline_if_needed();
+}
+
+fn render_table_row(output: &mut Rendered, tags: &[Tag<'_>]) {
+    let mut first = true;
+
+    for tag in tags {
+        if is_whitespace(tag) { continue; }
+
+        if !first && !output.add_if_within_limit(" | ") { return; }
+        first = false;
+
+        let inner = output.render_nested(std::slice::from_ref(tag), output.remaining);
+        output.add_rendered(&inner, inner.text.trim());
+    }
+}
+
+/// Whether the tag at `index` is the last thing on its line.
+fn ends_line(tags: &[Tag<'_>], index: usize) -> bool {
+    match tags.get(index + 1) {
+        Some(Tag::Text(text)) => text.trim_start_matches([' ', '\t']).starts_with('\n'),
+        Some(_) => false,
+        None => true
+    }
+}
+
+fn render_within(tags: &[Tag<'_>], limit: usize, code: bool) -> Rendered {
+    let mut output = Rendered::new(limit, code);
+
+    if limit == 0 {
+        output.truncated = !tags.is_empty();
+        return output;
+    }
+
+    for (index, tag) in tags.iter().enumerate() {
+        match tag {
+            Tag::Text(text) if output.code => {
+                output.add_until_limit(&text.replace('`', "ˋ"));
+            }
             Tag::Text(text) => {
-                add_until_limit(&mut chars, text.as_bytes(), &mut limit);
+                output.add_escaped_until_limit(&escape_markdown(text));
             }
             Tag::Bold(inner_tags) => {
-                let bytes = render_as_bytes(inner_tags, limit.saturating_sub(4));
-                wrap_lines(&mut chars, &bytes, "**".as_bytes(), "**".as_bytes(), &mut limit);
+                let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(4));
+                output.wrap_lines(&inner, &inner.text, "**", "**");
             },
             Tag::Italic(inner_tags) => {
-                let bytes = render_as_bytes(inner_tags, limit.saturating_sub(2));
-                wrap_lines(&mut chars, &bytes, "*".as_bytes(), "*".as_bytes(), &mut limit);
+                let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(2));
+                output.wrap_lines(&inner, &inner.text, "*", "*");
             },
             Tag::Underline(inner_tags) => {
-                let bytes = render_as_bytes(inner_tags, limit.saturating_sub(4));
-                wrap_lines(&mut chars, &bytes, "__".as_bytes(), "__".as_bytes(), &mut limit);
+                let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(4));
+                output.wrap_lines(&inner, &inner.text, "__", "__");
             },
             Tag::Strike(inner_tags) => {
-                let bytes = render_as_bytes(inner_tags, limit.saturating_sub(4));
-                wrap_lines(&mut chars, &bytes, "~~".as_bytes(), "~~".as_bytes(), &mut limit);
+                let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(4));
+                output.wrap_lines(&inner, &inner.text, "~~", "~~");
+            },
+            Tag::Sub(inner_tags) => {
+                render_small_text(&mut output, inner_tags, subscript, ends_line(tags, index));
             },
-            Tag::Sub(inner_tags) | Tag::Sup(inner_tags) | Tag::Spoiler((_, inner_tags)) => {
-                let bytes = render_as_bytes(inner_tags, limit);
-                add_until_limit(&mut chars, &bytes, &mut limit);
+            Tag::Sup(inner_tags) => {
+                render_small_text(&mut output, inner_tags, superscript, ends_line(tags, index));
             },
+            // Spoiler markers are shown literally inside code spans, so the content is left out there
+            Tag::Spoiler(_) if output.code => {
+                output.add_if_within_limit("[spoiler]");
+            },
+            Tag::Spoiler((title, inner_tags)) => {
+                if let Some(title) = title {
+                    output.add_newline_if_needed();
+                    if !output.add_if_within_limit(&format!("**{}**\n", escape_markdown(title))) {
+                        continue;
+                    }
+                }
+
+                let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(4));
+                let markers = 4 * inner.text.lines().filter(|line| !line.trim().is_empty()).count();
+
+                // Never show spoiler content without the spoiler markers around it. Each line gets its own markers,
+                // so formatting wrapped around the spoiler line by line stays balanced
+                if inner.text.is_empty() || char_count(&inner.text) + markers > output.remaining {
+                    output.truncated |= !inner_tags.is_empty();
+                } else {
+                    output.wrap_lines(&inner, &inner.text, "||", "||");
+                }
+            },
+            Tag::Color(inner_tags) | Tag::Size(inner_tags) | Tag::Font(inner_tags) |
+            Tag::Background(inner_tags) | Tag::Anchor(inner_tags) => {
+                let inner = output.render_nested(inner_tags, output.remaining);
+                output.add_rendered(&inner, &inner.text);
+            },
+            Tag::Nation(name) | Tag::Region(name) if output.code => {
+                output.add_if_within_limit(&prettify_name(name));
+            }
             Tag::Nation(name) => {
-                let string = display_nation(name, false);
-                add_if_within_limit(&mut chars, string.as_bytes(), &mut limit);
+                output.add_if_within_limit(&display_nation(name, false));
             }
             Tag::Region(name) => {
-                let string = display_region(name, false);
-                add_if_within_limit(&mut chars, string.as_bytes(), &mut limit);
+                output.add_if_within_limit(&display_region(name, false));
             }
             Tag::Proposal((id, inner_tags)) => {
                 let url = format!("https://www.nationstates.net/page=UN_view_proposal/id={id}");
 
-                let bytes = render_as_bytes(inner_tags, limit);
-                wrap_lines(
-                    &mut chars, &bytes, 
-                    "[".as_bytes(), 
-                    format!("]({url})").as_bytes(),
-                    &mut limit
-                );
+                let inner = output.render_nested(inner_tags, output.remaining);
+                output.wrap_lines(&inner, &inner.text, "[", &format!("]({url})"));
             }
             Tag::Resolution((chamber, id, inner_tags)) => {
-                let url = match chamber {
+                let url = match *chamber {
                     "UN" => format!("https://www.nationstates.net/page=WA_past_resolution/id={id}/un=1"),
                     "GA" => format!("https://www.nationstates.net/page=WA_past_resolution/id={id}/council=1"),
                     "SC" => format!("https://www.nationstates.net/page=WA_past_resolution/id={id}/council=2"),
                     _ => String::new(),
                 };
 
-                let bytes = render_as_bytes(inner_tags, limit);
-                wrap_lines(
-                    &mut chars, &bytes,
-                    "[".as_bytes(), 
-                    format!("]({url})").as_bytes(),
-                    &mut limit
-                );
+                let inner = output.render_nested(inner_tags, output.remaining);
+                output.wrap_lines(&inner, &inner.text, "[", &format!("]({url})"));
             },
             Tag::Url((url, inner_tags)) => {
-                let bytes = render_as_bytes(inner_tags, limit);
-                wrap_lines(
-                    &mut chars, &bytes, 
-                    "[".as_bytes(), 
-                    format!("]({url})").as_bytes(),
-                    &mut limit
-                );
+                let inner = output.render_nested(inner_tags, output.remaining);
+                match sanitize_url(url) {
+                    Some(url) => output.wrap_lines(&inner, &inner.text, "[", &format!("]({url})")),
+                    None => output.add_rendered(&inner, &inner.text)
+                }
+            },
+            Tag::Pre(inner_tags) if output.code => {
+                let inner = output.render_nested(inner_tags, output.remaining);
+                output.add_rendered(&inner, &inner.text);
             },
             Tag::Pre(inner_tags) => {
-                let bytes = render_as_bytes(inner_tags, limit.saturating_sub(2));
-                wrap_lines(&mut chars, &bytes, "`".as_bytes(), "`".as_bytes(), &mut limit);
+                let inner = render_within(inner_tags, output.remaining.saturating_sub(2), true);
+                output.wrap_lines(&inner, &inner.text, "`", "`");
             },
             Tag::Quote((nation, id, inner_tags)) => {
                 let url = format!("https://www.nationstates.net/page=rmb/postid={id}");
 
-                if let Some(char) = chars.last() && *char != b'\n' {
-                    add_if_within_limit(&mut chars, &[b'\n'], &mut limit);
-                }
+                output.add_newline_if_needed();
 
-                let bytes = render_as_bytes(inner_tags, limit.min(512));
-                if nation != "0" && id != "0" {
-                    add_if_within_limit(
-                        &mut chars, 
-                        format!("[Quoted from {}:]({})\n", prettify_name(nation), url).as_bytes(), 
-                        &mut limit
+                // Quotes are deliberately shortened, that alone doesn't make the post truncated
+                let mut inner = output.render_nested(inner_tags, output.remaining.min(MAX_QUOTE_LENGTH));
+                inner.truncated &= output.remaining <= MAX_QUOTE_LENGTH;
+
+                if *nation != "0" && *id != "0" {
+                    output.add_if_within_limit(
+                        &format!("[Quoted from {}:]({})\n", escape_markdown(&prettify_name(nation)), url)
                     );
                 }
 
-                wrap_lines(&mut chars, &bytes, "> ".as_bytes(), "".as_bytes(), &mut limit);
+                output.wrap_lines(&inner, &inner.text, "> ", "");
+            }
+            Tag::Align(inner_tags) => {
+                output.add_newline_if_needed();
+                let inner = output.render_nested(inner_tags, output.remaining);
+                output.add_rendered(&inner, &inner.text);
+                output.add_newline_if_needed();
+            }
+            Tag::List((ordered, inner_tags)) => {
+                render_list(&mut output, inner_tags, *ordered);
+            }
+            Tag::ListItem(_) => {
+                render_list(&mut output, std::slice::from_ref(tag), false);
+            }
+            Tag::Table(inner_tags) => {
+                output.add_newline_if_needed();
+
+                for tag in inner_tags {
+                    if is_whitespace(tag) { continue; }
+
+                    let inner = output.render_nested(std::slice::from_ref(tag), output.remaining);
+                    output.add_rendered(&inner, inner.text.trim());
+                    output.add_newline_if_needed();
+                }
+            }
+            Tag::TableRow(inner_tags) => {
+                render_table_row(&mut output, inner_tags);
+            }
+            Tag::TableCell((header, inner_tags)) => {
+                if *header {
+                    let inner = output.render_nested(inner_tags, output.remaining.saturating_sub(4));
+                    output.wrap_lines(&inner, inner.text.trim(), "**", "**");
+                } else {
+                    let inner = output.render_nested(inner_tags, output.remaining);
+                    output.add_rendered(&inner, &inner.text);
+                }
+            }
+            Tag::Box(inner_tags) => {
+                output.add_newline_if_needed();
+                let inner = output.render_nested(inner_tags, output.remaining);
+                output.wrap_lines(&inner, inner.text.trim(), "> ", "");
+                output.add_newline_if_needed();
+            }
+            Tag::Image(url) => {
+                if let Some(url) = sanitize_url(url) {
+                    output.add_if_within_limit(&format!("[Image]({url})"));
+                }
+            }
+            Tag::Rule => {
+                output.add_newline_if_needed();
+                output.add_if_within_limit("──────────\n");
             }
         }
     }
 
-    chars
+    output
 }
 
+/// Renders tags as Discord Markdown in at most `limit` characters.
+/// If the content doesn't fit, it is cut and a truncation marker is added at the end.
 pub fn render_tags(tags: Vec<Tag<'_>>, limit: usize) -> String {
-    let chars = render_as_bytes(tags, limit);
+    let output = render_within(&tags, limit, false);
+    if !output.truncated || limit <= char_count(TRUNCATION_MARKER) { return output.text; }
+
+    let output = render_within(&tags, limit.saturating_sub(char_count(TRUNCATION_MARKER)), false);
+    format!("{}{TRUNCATION_MARKER}", output.text.trim_end())
+}
+
+/// The visible text of a post without formatting, link targets, images or quotes, e.g. to match alerts against.
+pub fn plain_text(tags: &[Tag<'_>]) -> String {
+    let mut result = String::new();
+    collect_plain_text(tags, &mut result);
+    result
+}
+
+fn collect_plain_text(tags: &[Tag<'_>], result: &mut String) {
+    for tag in tags {
+        match tag {
+            Tag::Text(text) => result.push_str(text),
+            Tag::Nation(name) | Tag::Region(name) => result.push_str(&prettify_name(name)),
+            Tag::Quote(_) | Tag::Image(_) => {},
+            Tag::Spoiler((title, inner_tags)) => {
+                if let Some(title) = title {
+                    result.push_str(title);
+                    result.push('\n');
+                }
+                collect_plain_text(inner_tags, result);
+            },
+            // Keep separate cells, items and lines from running into one word
+            Tag::ListItem(_) | Tag::TableCell(_) | Tag::TableRow(_) | Tag::Box(_) | Tag::Rule => {
+                collect_plain_text(tag.children(), result);
+                result.push('\n');
+            },
+            _ => collect_plain_text(tag.children(), result)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nscode::parse;
+
+    #[test]
+    fn short_posts_are_not_truncated() {
+        assert_eq!(render_tags(parse("[b]héllo[/b] wörld"), 100), "**héllo** wörld");
+    }
+
+    #[test]
+    fn truncation_never_splits_characters() {
+        let text = "ü😀".repeat(100);
+        let output = render_tags(parse(&text), 50);
+
+        assert!(output.chars().count() <= 50);
+        assert!(output.ends_with(TRUNCATION_MARKER));
+        assert!(!output.contains('\u{FFFD}'));
+    }
 
-    String::from_utf8_lossy(&chars).to_string()
-}
\ No newline at end of file
+    #[test]
+    fn truncation_never_splits_markdown() {
+        let output = render_tags(parse(&format!("start [b]{}[/b]", "x".repeat(100))), 60);
+
+        assert!(output.chars().count() <= 60);
+        assert_eq!(output.matches("**").count() % 2, 0);
+        assert!(output.ends_with(TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn user_text_markdown_is_escaped() {
+        assert_eq!(
+            render_tags(parse("[b]*not italic*[/b] [x](https://evil.example) > quote"), 4096), 
+            "**\\*not italic\\*** \\[x\\](https://evil.example) > quote"
+        );
+        assert_eq!(render_tags(parse("> quoted\n# heading"), 4096), "\\> quoted\n\\# heading");
+    }
+
+    #[test]
+    fn links_and_code_are_not_escaped() {
+        assert_eq!(render_tags(parse("https://example.com/a_b_c"), 4096), "https://example.com/a_b_c");
+        assert_eq!(render_tags(parse("[pre]a_b*c`d[/pre]"), 4096), "`a_b*cˋd`");
+    }
+
+    #[test]
+    fn shortened_quotes_do_not_mark_the_post_truncated() {
+        let text = format!("[quote=testlandia;1]{}[/quote]reply", "x".repeat(1000));
+
+        assert!(!render_tags(parse(&text), 4096).contains(TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn small_text_falls_back_without_changing_case() {
+        assert_eq!(render_tags(parse("x[sup]2[/sup] H[sub]2[/sub]O"), 4096), "x² H₂O");
+        assert_eq!(render_tags(parse("[sub]Hello![/sub]\nmore text"), 4096), "-# Hello!\nmore text");
+        assert_eq!(render_tags(parse("[sub]Hello![/sub] more text"), 4096), "Hello! more text");
+        assert_eq!(render_tags(parse("[sup]ABC[/sup]"), 4096), "-# ABC");
+    }
+
+    #[test]
+    fn plain_text_leaves_out_link_targets_and_quotes() {
+        assert_eq!(
+            plain_text(&parse("[quote=testlandia;1]raid[/quote][url=https://raid.example]a [b]link[/b][/url] [nation]max_topia[/nation]")),
+            "a link max topia"
+        );
+    }
+
+    #[test]
+    fn first_image_outside_quotes_is_found() {
+        assert_eq!(
+            find_image(&parse("[quote=testlandia;1][img]https://a.example/q.png[/img][/quote] see https://b.example/x.JPG?s=1")),
+            Some("https://b.example/x.JPG?s=1".to_string())
+        );
+        assert_eq!(find_image(&parse("[b][img]/images/flag.svg[/img][/b]")), Some("https://www.nationstates.net/images/flag.svg".to_string()));
+        assert_eq!(find_image(&parse("https://example.com/page no images")), None);
+    }
+
+    fn render(text: &str) -> String {
+        render_tags(parse(text), 4096)
+    }
+
+    #[test]
+    fn lists_are_rendered_as_markdown_lists() {
+        assert_eq!(render("[list][*]one[*]two[/list]"), "- one\n- two\n");
+        assert_eq!(render("[list=1][*]one[*]two[/list]"), "1. one\n2. two\n");
+    }
+
+    #[test]
+    fn tables_are_rendered_one_row_per_line() {
+        assert_eq!(render("[table][tr][th]A[/th][th]B[/th][/tr][tr][td]1[/td][td]2[/td][/tr][/table]"), "**A** | **B**\n1 | 2\n");
+    }
+
+    #[test]
+    fn boxes_are_rendered_as_block_quotes() {
+        assert_eq!(render("[box]boxed[/box]"), "> boxed\n");
+    }
+
+    #[test]
+    fn rules_are_rendered_as_a_line() {
+        assert_eq!(render("above[hr]below"), "above\n──────────\nbelow");
+    }
+
+    #[test]
+    fn images_are_rendered_as_links() {
+        assert_eq!(render("[img]https://example.com/a.png[/img]"), "[Image](https://example.com/a.png)");
+    }
+
+    #[test]
+    fn styling_without_markdown_keeps_its_text() {
+        assert_eq!(render("[color=red]red[/color]"), "red");
+        assert_eq!(render("[size=20]big[/size]"), "big");
+        assert_eq!(render("[font=Arial]font[/font]"), "font");
+        assert_eq!(render("[background-block=red]background[/background-block]"), "background");
+        assert_eq!(render("[anchor=top]anchor[/anchor]"), "anchor");
+    }
+
+    #[test]
+    fn aligned_text_gets_its_own_line() {
+        assert_eq!(render("before [align=center]middle[/align] after"), "before \nmiddle\n after");
+        assert_eq!(render("[center]middle[/center]"), "middle\n");
+    }
+
+    #[test]
+    fn nation_and_region_tags_are_rendered_as_links() {
+        assert_eq!(render("[nation]testlandia[/nation]"), display_nation("testlandia", false));
+        assert_eq!(render("[nation=short]testlandia[/nation]"), display_nation("testlandia", false));
+        assert_eq!(render("[region]lazarus[/region]"), display_region("lazarus", false));
+    }
+
+    #[test]
+    fn links_to_pages_are_rendered() {
+        assert_eq!(render("[url=https://example.com]link[/url]"), "[link](https://example.com)");
+        assert_eq!(
+            render("[proposal=testlandia_123]proposal[/proposal]"), 
+            "[proposal](https://www.nationstates.net/page=UN_view_proposal/id=testlandia_123)"
+        );
+        assert_eq!(
+            render("[resolution=GA#2]resolution[/resolution]"), 
+            "[resolution](https://www.nationstates.net/page=WA_past_resolution/id=2/council=1)"
+        );
+    }
+
+    #[test]
+    fn spoilers_in_code_spans_are_hidden() {
+        assert_eq!(render("[pre]before [spoiler]secret[/spoiler] after[/pre]"), "`before [spoiler] after`");
+    }
+
+    #[test]
+    fn spoilers_are_hidden_line_by_line() {
+        assert_eq!(render("[spoiler]one\ntwo[/spoiler]"), "||one||\n||two||");
+        assert_eq!(render("[b][spoiler]one\ntwo[/spoiler][/b]"), "**||one||**\n**||two||**");
+        assert_eq!(render("[i]a [spoiler]one\n\ntwo[/spoiler][/i]"), "*a ||one||*\n\n*||two||*");
+    }
+
+    #[test]
+    fn preformatted_text_is_a_code_span() {
+        assert_eq!(render("[pre]code[/pre]"), "`code`");
+    }
+}
@@ -1,38 +1,154 @@
 use serenity::all::{CreateButton, Http};
 use std::error::Error;
 
-use caramel::{ns::{UserAgent, format::prettify_name}, types::akari::Event};
+use caramel::{ns::{UserAgent, format::prettify_name}, types::akari::Event, webhook::execute_webhook};
 
-use crate::{render::render_tags, webhook::{build_event_embed, send_embed_to_webhook}};
-use crate::{config::OutputConfig, nscode};
+use crate::cache::{CachedPost, Digest, DigestPost, NSCache, TrackedMessage};
+use crate::{render::{find_image, render_tags}, utils::{DISCORD_INVITE_DOMAINS, display_alert_label, display_nation, display_region, suppress_links}};
+use crate::webhook::{
+    build_compact_message, build_embed_message, build_event_embed, build_text_message, 
+    ping_prefix, replace_webhook_message, send_tracked_message
+};
+use crate::{config::{InviteMode, OutputConfig, OutputStyle}, nscode::{self, Tag}, output::build_custom_buttons};
 
 const MAX_DISCORD_URL_LENGTH: usize = 512;
+const QUOTE_TRUNCATION_MARKER: &str = " [...]";
 
+/// Tags that need a closing tag, so cutting a quote between the two would leave it unbalanced.
+/// These are spelled exactly as nscode.pest and `nscode::parse` accept them.
+const CLOSED_TAGS: &[&str] = &[
+    "b", "i", "u", "strike", "sub", "sup", "pre", "box", "table", "tr", "th", "td",
+    "center", "left", "right", "align", "color", "size", "font", "background-block",
+    "anchor", "list", "spoiler", "url", "proposal", "resolution", "quote", "nation", "region", "img"
+];
+
+fn build_quote_link(region: &str, nation: &str, postid: &str, quote_content: &str, generated_by: &str) -> String {
+    let quote = format!("[quote={};{}]{}[/quote]\n", nation, postid, quote_content);
+
+    format!(
+        "https://www.nationstates.net/page=display_region_rmb/region={}?generated_by={}&message={}#editor", 
+        region, generated_by, urlencoding::encode(&quote).into_owned()
+    )
+}
+
+/// Returns the closing tags for the tags left open in `text`, innermost first.
+fn closing_tags(text: &str) -> String {
+    let mut open: Vec<&str> = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('[') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find(']') else { break };
+
+        let (closing, tag) = match rest[..end].strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, &rest[..end])
+        };
+
+        let name = tag.split(['=', ' ']).next().unwrap_or("");
+
+        if CLOSED_TAGS.contains(&name) {
+            if !closing {
+                open.push(name);
+            } else if let Some(index) = open.iter().rposition(|tag| *tag == name) {
+                open.truncate(index);
+            }
+        }
+    }
+
+    open.iter().rev().map(|name| format!("[/{name}]")).collect()
+}
+
+/// Returns the byte indices where a quote can be cut, largest first: before each run of whitespace
+/// outside of tags, or if `words` is false, at any character boundary outside of tags.
+fn cut_points(text: &str, words: bool) -> Vec<usize> {
+    let mut result = Vec::new();
+    let mut in_tag = false;
+    let mut previous = ' ';
+
+    for (index, c) in text.char_indices() {
+        if !in_tag && (!words || (c.is_whitespace() && !previous.is_whitespace())) {
+            result.push(index);
+        }
+
+        match c {
+            '[' => in_tag = true,
+            ']' => in_tag = false,
+            _ => {}
+        }
+
+        previous = c;
+    }
+
+    result.reverse();
+    result
+}
+
+/// Builds a link that opens the RMB editor with a post quoted. Posts too long for Discord's URL limit
+/// are cut at the last word that fits, with their tags closed and a marker showing the quote was shortened.
 fn generate_quote_link(
     region: &str,
     nation: &str,
     postid: &str,
     quote_content: &str,
-    user_agent: &UserAgent
+    generated_by: &str
 ) -> String {
-    let quote = format!("[quote={};{}]{}[/quote]\n", nation, postid, quote_content);
+    let url = build_quote_link(region, nation, postid, quote_content, generated_by);
 
-    let url = format!(
-        "https://www.nationstates.net/page=display_region_rmb/region={}?generated_by={}&message={}#editor", 
-        region, user_agent.web(), urlencoding::encode(&quote).into_owned()
-    );
+    if url.len() < MAX_DISCORD_URL_LENGTH {
+        return url;
+    }
 
-    if url.len() >= MAX_DISCORD_URL_LENGTH {
-        return generate_quote_link(region, nation, postid, "- snip -", user_agent);
+    // Every character takes at least one byte in the URL, so longer prefixes can never fit
+    let end = quote_content.char_indices().nth(MAX_DISCORD_URL_LENGTH).map_or(quote_content.len(), |(index, _)| index);
+    let content = &quote_content[..end];
+
+    for words in [true, false] {
+        for cut in cut_points(content, words) {
+            let prefix = content[..cut].trim_end();
+            let shortened = format!("{prefix}{QUOTE_TRUNCATION_MARKER}{}", closing_tags(prefix));
+            let url = build_quote_link(region, nation, postid, &shortened, generated_by);
+
+            if url.len() < MAX_DISCORD_URL_LENGTH {
+                return url;
+            }
+        }
     }
 
-    url
+    build_quote_link(region, nation, postid, QUOTE_TRUNCATION_MARKER.trim_start(), generated_by)
+}
+
+const MAX_PARENT_EXCERPT: usize = 300;
+const MAX_DIGEST_EXCERPT: usize = 150;
+
+/// A post quoted by a reply, with the cached post if it's recent enough to still be known.
+struct Parent {
+    nation: String,
+    post: Option<CachedPost>,
+}
+
+/// Finds the posts quoted at the top level of a post, skipping quotes that don't name a post.
+async fn find_parents(tags: &[Tag<'_>], cache: &NSCache) -> Vec<Parent> {
+    let posts = cache.rmb_posts.read().await;
+
+    tags.iter().filter_map(|tag| match tag {
+        Tag::Quote((nation, postid, _)) if *postid != "0" => {
+            let post = posts.get(postid).cloned();
+            let nation = post.as_ref().map_or_else(
+                || nation.to_lowercase().replace(' ', "_"), |post| post.nation.clone()
+            );
+
+            Some(Parent { nation, post })
+        },
+        _ => None
+    }).collect()
 }
 
 pub async fn output_rmb_post(
     http: &Http,
     output_config: &OutputConfig,
     event: &Event,
+    cache: &NSCache,
     user_agent: &UserAgent
 ) -> Result<(), Box<dyn Error>> {
     let nation = event.actor.as_ref().unwrap();
@@ -40,54 +156,385 @@ pub async fn output_rmb_post(
     let postid = &event.data[0];
     let message = &event.data[1];
 
-    let (content, quote_content) = format_content(message);
+    let parents = find_parents(&nscode::parse(message), cache).await;
+
+    if !output_config.reply_to_watched.is_empty()
+    && !parents.iter().any(|parent| output_config.reply_to_watched.contains(&parent.nation)) {
+        return Ok(());
+    }
+
+    if output_config.digest.is_some() {
+        let excerpt = digest_excerpt(message, output_config.invites, output_config.style == OutputStyle::Compact);
+
+        cache.add_to_digest(region, output_config, event.time, DigestPost { 
+            postid: postid.clone(), nation: nation.clone(), excerpt 
+        }).await;
+
+        return Ok(());
+    }
+
+    let reply = match parents.first() {
+        Some(parent) => format!(" replying to **{}**", prettify_name(&parent.nation)),
+        None => String::new()
+    };
+
+    let alert = match &output_config.alert {
+        Some(label) => format!(" matching {}", display_alert_label(label)),
+        None => String::new()
+    };
+
+    let header = build_compact_message(
+        &format!("New post on {}'s RMB by **{}**{}{}:\n", 
+            suppress_links(&display_region(region, true)), prettify_name(nation), reply, alert
+        ), 
+        event.time, output_config.timestamp.as_deref()
+    );
+
+    let limit = match output_config.style {
+        OutputStyle::Embed => MAX_DISCORD_EMBED_CONTENT,
+        OutputStyle::Compact => MAX_DISCORD_MESSAGE_CONTENT
+            .saturating_sub(header.chars().count())
+            .saturating_sub(ping_prefix(&output_config.mentions).chars().count()),
+    };
+
+    let (content, quote_content) = format_content(message, limit, output_config);
 
     let mut buttons: Vec<CreateButton> = Vec::new();
     
-    buttons.push(
-        CreateButton::new_link(
-            format!(
-                "https://www.nationstates.net/page=display_region_rmb/region={}?generated_by={}&postid={}#p{}", 
-                region, user_agent.web(), postid, postid
-            )
-        ).label("View Post")
-    );
+    if output_config.default_buttons {
+        buttons.push(
+            CreateButton::new_link(
+                format!(
+                    "https://www.nationstates.net/page=display_region_rmb/region={}?generated_by={}&postid={}#p{}", 
+                    region, user_agent.web(), postid, postid
+                )
+            ).label("View Post")
+        );
 
-    buttons.push(
-        CreateButton::new_link(
-            generate_quote_link(region, nation, postid, &quote_content, user_agent)
-        ).label("Quote Post")
-    );
+        buttons.push(
+            CreateButton::new_link(
+                generate_quote_link(region, nation, postid, &quote_content, &user_agent.web())
+            ).label("Quote Post")
+        );
+    }
+
+    buttons.extend(build_custom_buttons(output_config, event, user_agent));
+
+    let message = if output_config.style == OutputStyle::Compact {
+        build_text_message(output_config.mentions.clone(), &format!("{header}{content}"), buttons)
+    } else {
+        let footer = format!("Posted by {}", prettify_name(&nation));
+
+        let mut embed = build_event_embed(
+            output_config.color, &content, event.time, Some(&footer)
+        )?.title(
+            format!("New post on {}'s RMB{}", prettify_name(&region), alert)
+        );
+
+        // Without the quoted post cached, the field still says who the post replies to
+        if let Some(Parent { nation: parent, post }) = parents.first() {
+            let excerpt = post.as_ref().map_or(String::new(), |post| format_content(
+                &nscode::remove_subquotes(&post.content), MAX_PARENT_EXCERPT, output_config
+            ).0);
+
+            let excerpt = if excerpt.trim().is_empty() { "\u{200B}".to_string() } else { excerpt };
+            embed = embed.field(format!("Replying to {}", prettify_name(parent)), excerpt, false);
+        }
+
+        if let Some(image) = find_image(&nscode::parse(message)) {
+            embed = embed.image(image);
+        }
+
+        if output_config.flag && let Some(flag) = cache.flags.read().await.get(nation) {
+            embed = embed.thumbnail(flag);
+        }
+
+        build_embed_message(output_config.mentions.clone(), embed, buttons)
+    };
+
+    // Keep track of the message so it can be updated if the post is suppressed or deleted
+    let Some(hook) = &output_config.hook_token else {
+        return execute_webhook(http, &output_config.hook, message).await;
+    };
+
+    // Kept as its own statement: in an `if let`, the boxed error temporary would live across the cache await below,
+    // making the future non-Send, and `output_event` also runs in spawned workers like the tally worker
+    let message = send_tracked_message(http, hook, message).await?;
+    if let Some(message) = message {
+        cache.rmb_messages.write().await.entry(postid).push(TrackedMessage { hook: hook.clone(), message });
+    }
+
+    Ok(())
+}
+
+/// Sends the posts buffered in a digest as a single message, with one line per post.
+pub async fn output_rmb_digest(http: &Http, digest: &Digest) -> Result<(), Box<dyn Error>> {
+    let output_config = &digest.output_config;
+    let region = &digest.region;
+
+    let compact = output_config.style == OutputStyle::Compact;
+    let suppress = |text: String| if compact { suppress_links(&text) } else { text };
+
+    let mut content = suppress(format!("{} new post{} on {}'s RMB by {} nation{}:\n", 
+        digest.postids.len(), if digest.postids.len() == 1 { "" } else { "s" },
+        display_region(region, true),
+        digest.authors.len(), if digest.authors.len() == 1 { "" } else { "s" }
+    ));
+
+    // Leave room for the role pings and timestamp in front of compact messages
+    let limit = if compact { 
+        let prefix = ping_prefix(&output_config.mentions) + &build_compact_message("", digest.time, output_config.timestamp.as_deref());
+        MAX_DISCORD_MESSAGE_CONTENT.saturating_sub(prefix.chars().count())
+    } else { 
+        MAX_DISCORD_EMBED_CONTENT 
+    };
+
+    let mut listed = 0;
+    for post in &digest.posts {
+        let line = suppress(format!("- {}: {} ([view](https://www.nationstates.net/page=display_region_rmb/region={}?postid={}#p{}))\n", 
+            display_nation(&post.nation, true), post.excerpt, region, post.postid, post.postid
+        ));
+        let more = format!("…and {} more", digest.postids.len() - listed);
 
-    let footer = format!("Posted by {}", prettify_name(&nation));
+        if content.chars().count() + line.chars().count() + more.chars().count() > limit {
+            break;
+        }
 
-    let embed = build_event_embed(
-        output_config.color, &content, event.time, Some(&footer)
-    )?.title(
-        format!("New post on {}'s RMB", prettify_name(&region))
+        content.push_str(&line);
+        listed += 1;
+    }
+
+    // Posts past the digest's cap or that didn't fit in the message
+    if listed < digest.postids.len() {
+        content.push_str(&format!("…and {} more", digest.postids.len() - listed));
+    }
+
+    if compact {
+        let content = build_compact_message(&content, digest.time, output_config.timestamp.as_deref());
+        let message = build_text_message(output_config.mentions.clone(), &content, Vec::new());
+        return execute_webhook(http, &output_config.hook, message).await;
+    }
+
+    let embed = build_event_embed(output_config.color, &content, digest.time, None)?
+        .title(format!("RMB digest for {}", prettify_name(region)));
+
+    execute_webhook(http, &output_config.hook, build_embed_message(output_config.mentions.clone(), embed, Vec::new())).await
+}
+
+/// Replaces the message sent for a suppressed or deleted post with the given description.
+/// Returns false if no message was sent for that post on this webhook, or it isn't remembered anymore.
+pub async fn update_rmb_post(
+    http: &Http,
+    output_config: &OutputConfig,
+    event: &Event,
+    cache: &NSCache,
+    description: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let (Some(hook), Some(postid)) = (&output_config.hook_token, event.data.first()) else {
+        return Ok(false);
+    };
+
+    let Some(tracked) = cache.rmb_messages.read().await.get(postid)
+        .and_then(|messages| messages.iter().find(|message| message.hook == *hook)).cloned() else {
+        return Ok(false);
+    };
+
+    let content = build_compact_message(
+        &suppress_links(description), event.time, output_config.timestamp.as_deref()
     );
 
-    send_embed_to_webhook(
-        http,
-        &output_config.hook,
-        output_config.mentions.clone(),
-        embed,
-        buttons
-    ).await
+    replace_webhook_message(http, &tracked.hook, tracked.message, &content).await?;
+
+    Ok(true)
 }
 
 const MAX_DISCORD_EMBED_CONTENT: usize = 4096;
+const MAX_DISCORD_MESSAGE_CONTENT: usize = 2000;
+
+/// Defangs user and role mentions, `@everyone` and `@here`, so they show as plain text.
+fn sanitize_mentions(text: &str) -> String {
+    text.replace("<@", "<@\u{200B}")
+        .replace("<#", "<#\u{200B}")
+        .replace("@everyone", "@\u{200B}everyone")
+        .replace("@here", "@\u{200B}here")
+}
 
+/// Strips Discord invite links, or breaks them up so Discord doesn't turn them into invites.
+fn sanitize_invites(text: &str, mode: InviteMode) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+
+    while let Some((index, domain)) = DISCORD_INVITE_DOMAINS.iter()
+        .filter_map(|domain| lower[position..].find(domain).map(|index| (position + index, domain)))
+        .min_by_key(|(index, _)| *index) 
+    {
+        let mut start = index;
+        for prefix in ["www.", "https://", "http://"] {
+            if lower[position..start].ends_with(prefix) {
+                start -= prefix.len();
+            }
+        }
+
+        let code_start = index + domain.len();
+        let end = text[code_start..]
+            .find(|c: char| c.is_whitespace() || ")]>|*_~`\\".contains(c))
+            .map_or(text.len(), |offset| code_start + offset);
+
+        result.push_str(&text[position..start]);
+
+        match mode {
+            InviteMode::Strip => result.push_str("(invite removed)"),
+            InviteMode::Defang => {
+                result.push_str(&text[index..code_start].replacen('.', "[.]", 1));
+                result.push_str(&text[code_start..end]);
+            }
+        }
+
+        position = end;
+    }
+
+    result.push_str(&text[position..]);
+    result
+}
+
+/// Renders a post within `limit` characters, with mentions and invites sanitized.
+/// Sanitizing (and suppressing link previews for compact messages) makes the text longer, 
+/// so the render limit is lowered until the result fits.
 pub fn format_content(
-    content: &String
+    content: &String, limit: usize, output_config: &OutputConfig
 ) -> (String, String) {
-    let quote_content = nscode::remove_subquotes(content);
+    let compact = output_config.style == OutputStyle::Compact;
+    (render_content(content, limit, output_config.invites, compact), nscode::remove_subquotes(content))
+}
+
+fn render_content(content: &str, limit: usize, invites: InviteMode, compact: bool) -> String {
+    let mut render_limit = limit;
+
+    loop {
+        let mut fmt = render_tags(nscode::parse(content), render_limit);
+        fmt = sanitize_invites(&sanitize_mentions(&fmt), invites);
+
+        if compact {
+            fmt = suppress_links(&fmt);
+        }
+
+        let length = fmt.chars().count();
+
+        if length <= limit || render_limit == 0 {
+            return fmt;
+        }
+
+        render_limit = render_limit.saturating_sub(length - limit);
+    }
+}
+
+/// The first line of a post for a digest. Quotes are left out, so replies show their own text rather than the quote's
+/// header, and every rendered line has its own Markdown and spoiler markers, so cutting after the first one is safe.
+fn digest_excerpt(message: &str, invites: InviteMode, compact: bool) -> String {
+    let excerpt = render_content(&nscode::remove_subquotes(message), MAX_DIGEST_EXCERPT, invites, compact);
+    excerpt.lines().find(|line| !line.trim().is_empty()).unwrap_or("").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::is_discord_invite;
+
+    fn quoted_message(url: &str) -> String {
+        let message = url.split_once("&message=").unwrap().1.strip_suffix("#editor").unwrap();
+        urlencoding::decode(message).unwrap().into_owned()
+    }
+
+    #[test]
+    fn short_posts_are_quoted_in_full() {
+        let url = generate_quote_link("testregionia", "testlandia", "1", "Hello [b]world[/b]", "bubble");
+
+        assert_eq!(quoted_message(&url), "[quote=testlandia;1]Hello [b]world[/b][/quote]\n");
+    }
+
+    #[test]
+    fn long_posts_are_cut_at_a_word() {
+        let content = "lorem ipsum dolor sit amet ".repeat(50);
+        let url = generate_quote_link("testregionia", "testlandia", "1", &content, "bubble");
+        let message = quoted_message(&url);
+
+        assert!(url.len() < MAX_DISCORD_URL_LENGTH);
+        assert!(message.ends_with(" [...][/quote]\n"));
+
+        let body = message.strip_prefix("[quote=testlandia;1]").unwrap().strip_suffix(" [...][/quote]\n").unwrap();
+        assert!(content.starts_with(&format!("{body} ")));
+        assert!(body.len() > 100);
+    }
+
+    #[test]
+    fn long_non_ascii_posts_fit() {
+        let content = "日本語のテキスト 🎉🎉 ".repeat(40);
+        let url = generate_quote_link("testregionia", "testlandia", "1", &content, "bubble");
+        let message = quoted_message(&url);
+
+        assert!(url.len() < MAX_DISCORD_URL_LENGTH);
+        assert!(message.contains("日本語"));
+        assert!(message.ends_with(" [...][/quote]\n"));
+    }
+
+    #[test]
+    fn cut_quotes_keep_tags_balanced() {
+        let content = format!("[b][i]{}[/i][/b] [url=https://example.com/a b]link[/url]", "word ".repeat(200));
+        let message = quoted_message(&generate_quote_link("testregionia", "testlandia", "1", &content, "bubble"));
 
-    if let Some(tags) = nscode::parse(content) {
-        let fmt = render_tags(tags, MAX_DISCORD_EMBED_CONTENT);
+        assert!(message.ends_with(" [...][/i][/b][/quote]\n"));
+    }
+
+    #[test]
+    fn quotes_cut_inside_background_blocks_are_closed() {
+        let content = format!("[background-block=#000000][color=white]{}[/color][/background-block]", "word ".repeat(200));
+        let message = quoted_message(&generate_quote_link("testregionia", "testlandia", "1", &content, "bubble"));
+
+        assert!(message.ends_with(" [...][/color][/background-block][/quote]\n"));
+    }
+
+    #[test]
+    fn posts_without_spaces_are_cut_at_a_character() {
+        let content = "é".repeat(1000);
+        let url = generate_quote_link("testregionia", "testlandia", "1", &content, "bubble");
 
-        return (fmt, quote_content);
+        assert!(url.len() < MAX_DISCORD_URL_LENGTH);
+        assert!(quoted_message(&url).contains("éé [...]"));
     }
 
-    ("**Error: unable to parse RMB post, view the post by clicking the 'View Post' button**".into(), quote_content)
-}
\ No newline at end of file
+    #[test]
+    fn mentions_are_defanged() {
+        let text = sanitize_mentions("@everyone @here <@123> <@!123> <@&456> <#789>");
+
+        assert!(!text.contains("@everyone") && !text.contains("@here"));
+        assert!(!text.contains("<@1") && !text.contains("<@!") && !text.contains("<@&") && !text.contains("<#7"));
+        assert_eq!(text.replace('\u{200B}', ""), "@everyone @here <@123> <@!123> <@&456> <#789>");
+    }
+
+    #[test]
+    fn invites_are_defanged_or_stripped() {
+        for domain in DISCORD_INVITE_DOMAINS {
+            let text = format!("join https://{}AbC123 now", domain.to_uppercase());
+
+            let defanged = sanitize_invites(&text, InviteMode::Defang);
+            assert!(!is_discord_invite(&defanged), "{defanged}");
+            assert!(defanged.contains("[.]") && defanged.ends_with("AbC123 now"), "{defanged}");
+
+            assert_eq!(sanitize_invites(&text, InviteMode::Strip), "join (invite removed) now");
+        }
+
+        assert_eq!(sanitize_invites("no invites here", InviteMode::Strip), "no invites here");
+    }
+
+    #[test]
+    fn digest_excerpts_keep_spoilers_hidden() {
+        assert_eq!(digest_excerpt("[spoiler]secret line one\nsecret line two[/spoiler]", InviteMode::Defang, false), "||secret line one||");
+        assert_eq!(digest_excerpt("[b][spoiler]one\ntwo[/spoiler][/b]", InviteMode::Defang, false), "**||one||**");
+    }
+
+    #[test]
+    fn digest_excerpts_of_replies_leave_out_the_quote() {
+        assert_eq!(digest_excerpt("[quote=testlandia;123]Original post\nmore[/quote]\nMy reply\nsecond line", InviteMode::Defang, false), "My reply");
+    }
+}
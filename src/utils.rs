@@ -1,13 +1,97 @@
 use caramel::ns::format::{nation_link, region_link, prettify_name};
 
+/// Escapes Discord Markdown in user-provided text, so it is shown literally.
+/// Bare links are left alone so Discord still recognizes them.
+pub fn escape_markdown(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut line_start = true;
+    let mut in_link = false;
+
+    for (index, c) in text.char_indices() {
+        if c.is_whitespace() {
+            in_link = false;
+        } else if !in_link {
+            let rest = &text[index..];
+            in_link = rest.starts_with("https://") || rest.starts_with("http://");
+        }
+
+        let escape = match c {
+            _ if in_link => false,
+            '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']' => true,
+            '>' | '#' | '-' | '+' => line_start,
+            _ => false
+        };
+
+        if escape {
+            result.push('\\');
+        }
+        result.push(c);
+
+        if c == '\n' {
+            line_start = true;
+        } else if c != ' ' && c != '\t' {
+            line_start = false;
+        }
+    }
+
+    result
+}
+
+/// Decodes the XML entities in text from the NationStates API and dumps, leaving unknown ones as they are.
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find(';') else { break };
+        let entity = &rest[1..end];
+
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x").map_or_else(
+                || entity.strip_prefix('#').and_then(|n| n.parse().ok()),
+                |n| u32::from_str_radix(n, 16).ok()
+            ).and_then(char::from_u32)
+        };
+
+        match c {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+pub const DISCORD_INVITE_DOMAINS: &[&str] = &["discord.gg/", "discord.com/invite/", "discordapp.com/invite/"];
+
+pub fn is_discord_invite(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    DISCORD_INVITE_DOMAINS.iter().any(|domain| url.contains(domain))
+}
+
 pub fn display_nation(name: &str, bold: bool) -> String {
-    let display = prettify_name(name);
+    let display = escape_markdown(&prettify_name(name));
     let fmt = if bold { format!("**{display}**") } else { display };
     format!("[{fmt}]({})", nation_link(name))
 }
 
 pub fn display_region(name: &str, bold: bool) -> String {
-    let display = prettify_name(name);
+    let display = escape_markdown(&prettify_name(name));
     let fmt = if bold { format!("**{display}**") } else { display };
     format!("[{fmt}]({})", region_link(name))
 }
@@ -26,20 +110,108 @@ pub fn display_chamber(chamber: &str, bold: bool) -> String {
 }
 
 pub fn display_proposal_name(name: &str) -> String {
-    format!("`{}`", name)
+    format!("`{}`", name.replace('`', "ˋ"))
 }
 
-pub fn display_proposal_url(name: &str, chamber: &str, id: &str, bold: bool) -> String {
-    let council = match chamber {
+/// The council number the NationStates API and URLs use for a chamber.
+pub fn council_id(chamber: &str) -> &'static str {
+    match chamber {
         "General Assembly" => "1",
         "Security Council" => "2",
         _ => "0"
-    };
+    }
+}
+
+const MAX_ALERT_LABEL_LENGTH: usize = 64;
+
+/// Shows the word or pattern an alert matched, shortened so a long pattern still fits in an embed title.
+pub fn display_alert_label(label: &str) -> String {
+    let label = label.replace('`', "ˋ");
+
+    match label.char_indices().nth(MAX_ALERT_LABEL_LENGTH) {
+        Some((index, _)) => format!("`{}…`", &label[..index]),
+        None => format!("`{label}`")
+    }
+}
+
+pub fn display_proposal_url(name: &str, chamber: &str, id: &str, bold: bool) -> String {
+    let council = council_id(chamber);
 
     let fmt = format!(
         "[{}](https://www.nationstates.net/page=WA_past_resolution/id={}/council={})", 
-        name, id, council
+        escape_markdown(name), id, council
     );
     
     if bold { format!("**{fmt}**") } else { fmt }
-}
\ No newline at end of file
+}
+
+/// Adds the `generated_by` query parameter to a NationStates URL, keeping any `#anchor` at the end.
+pub fn add_generated_by(url: &str, generated_by: &str) -> String {
+    let (base, anchor) = match url.find('#') {
+        Some(index) => url.split_at(index),
+        None => (url, "")
+    };
+
+    let separator = if base.contains('?') { '&' } else { '?' };
+
+    format!("{base}{separator}generated_by={generated_by}{anchor}")
+}
+
+/// Wraps the target of every Markdown link in angle brackets, so Discord doesn't generate link previews for it.
+pub fn suppress_links(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find("](") {
+        let (before, after) = rest.split_at(index + 2);
+        result.push_str(before);
+
+        let escaped = before.ends_with("\\](");
+
+        match after.find(')') {
+            Some(end) if !escaped && !after.starts_with('<') => {
+                result.push('<');
+                result.push_str(&after[..end]);
+                result.push('>');
+                rest = &after[end..];
+            },
+            _ => rest = after
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_by_starts_the_query() {
+        assert_eq!(
+            add_generated_by("https://www.nationstates.net/nation=testlandia", "bubble"),
+            "https://www.nationstates.net/nation=testlandia?generated_by=bubble"
+        );
+    }
+
+    #[test]
+    fn generated_by_extends_an_existing_query() {
+        assert_eq!(
+            add_generated_by("https://www.nationstates.net/page=display_region_rmb/region=lazarus?postid=1", "bubble"),
+            "https://www.nationstates.net/page=display_region_rmb/region=lazarus?postid=1&generated_by=bubble"
+        );
+    }
+
+    #[test]
+    fn generated_by_goes_before_the_anchor() {
+        assert_eq!(
+            add_generated_by("https://www.nationstates.net/region=lazarus?postid=1#p1", "bubble"),
+            "https://www.nationstates.net/region=lazarus?postid=1&generated_by=bubble#p1"
+        );
+        assert_eq!(
+            add_generated_by("https://www.nationstates.net/region=lazarus#rmb", "bubble"),
+            "https://www.nationstates.net/region=lazarus?generated_by=bubble#rmb"
+        );
+    }
+}
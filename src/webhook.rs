@@ -1,11 +1,40 @@
 use hex_color::HexColor;
 use serenity::all::{
-    CreateActionRow, CreateAllowedMentions, CreateButton, CreateEmbed, 
-    CreateEmbedFooter, ExecuteWebhook, Http, Mentionable, RoleId, Timestamp
+    CreateActionRow, CreateAllowedMentions, CreateButton, CreateEmbed, CreateEmbedFooter, 
+    ChannelId, EditWebhookMessage, ExecuteWebhook, Http, Mentionable, MessageId, RoleId, Timestamp, WebhookId
 };
+use serenity::builder::Builder;
+use std::time::Duration;
+use log::warn;
 
 use caramel::webhook::{Webhook, execute_webhook};
 
+/// The ID and token of a webhook, needed to get back the messages it sends and edit them later.
+/// `thread` is the thread the webhook's URL posts to, if it has a `thread_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookToken {
+    pub id: WebhookId,
+    pub token: String,
+    pub thread: Option<ChannelId>,
+}
+
+pub fn parse_webhook_token(url: &str) -> Option<WebhookToken> {
+    let (_, path) = url.split_once("/api/webhooks/")?;
+    let (id, token) = path.split_once('/')?;
+    let id = id.parse::<u64>().ok().filter(|id| *id != 0)?;
+    let token = token.split(['/', '?']).next()?;
+
+    if token.is_empty() { return None; }
+
+    let thread = url.split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|param| param.strip_prefix("thread_id=")))
+        .and_then(|thread| thread.parse::<u64>().ok())
+        .filter(|thread| *thread != 0)
+        .map(ChannelId::new);
+
+    Some(WebhookToken { id: WebhookId::new(id), token: token.to_string(), thread })
+}
+
 pub fn build_event_embed(
     color: Option<HexColor>, description: &str, timestamp: u64, footer: Option<&str>
 ) -> Result<CreateEmbed, Box<dyn std::error::Error>> {
@@ -23,13 +52,30 @@ pub fn build_event_embed(
     Ok(embed)
 }
 
-pub async fn send_embed_to_webhook(
-    http: &Http,
-    webhook: &Webhook,
+const MAX_BUTTONS_PER_ROW: usize = 5;
+const MAX_ACTION_ROWS: usize = 5;
+
+fn button_rows(buttons: Vec<CreateButton>) -> Vec<CreateActionRow> {
+    buttons.chunks(MAX_BUTTONS_PER_ROW)
+        .take(MAX_ACTION_ROWS)
+        .map(|row| CreateActionRow::Buttons(row.to_vec()))
+        .collect()
+}
+
+pub fn build_compact_message(
+    description: &str, timestamp: u64, format: Option<&str>
+) -> String {
+    match format {
+        Some(style) => format!("<t:{timestamp}:{style}> {description}"),
+        None => description.to_string()
+    }
+}
+
+pub fn build_embed_message(
     mentions: Vec<u64>,
     embed: CreateEmbed,
     buttons: Vec<CreateButton>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> ExecuteWebhook {
     let roles: Vec<RoleId> = mentions.into_iter().map(RoleId::new).collect();
 
     let mut message = ExecuteWebhook::new().embed(embed).content(
@@ -39,10 +85,151 @@ pub async fn send_embed_to_webhook(
     );
 
     if !buttons.is_empty() {
-        message = message.components(vec![
-            CreateActionRow::Buttons(buttons)
-        ]);
+        message = message.components(button_rows(buttons));
+    }
+
+    message
+}
+
+/// The role pings put in front of a text message, which count towards Discord's message length limit.
+pub fn ping_prefix(mentions: &[u64]) -> String {
+    let mut pings = mentions.iter().map(|id| RoleId::new(*id).mention().to_string()).collect::<Vec<String>>().join(" ");
+    if !pings.is_empty() {
+        pings.push(' ');
+    }
+
+    pings
+}
+
+pub fn build_text_message(
+    mentions: Vec<u64>,
+    content: &str,
+    buttons: Vec<CreateButton>,
+) -> ExecuteWebhook {
+    let pings = ping_prefix(&mentions);
+    let roles: Vec<RoleId> = mentions.into_iter().map(RoleId::new).collect();
+
+    let mut message = ExecuteWebhook::new().content(
+        format!("{pings}{content}")
+    ).allowed_mentions(
+        CreateAllowedMentions::new().roles(roles)
+    );
+
+    if !buttons.is_empty() {
+        message = message.components(button_rows(buttons));
+    }
+
+    message
+}
+
+pub async fn send_embed_to_webhook(
+    http: &Http,
+    webhook: &Webhook,
+    mentions: Vec<u64>,
+    embed: CreateEmbed,
+    buttons: Vec<CreateButton>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    execute_webhook(http, webhook, build_embed_message(mentions, embed, buttons)).await
+}
+
+pub async fn send_message_to_webhook(
+    http: &Http,
+    webhook: &Webhook,
+    mentions: Vec<u64>,
+    content: &str,
+    buttons: Vec<CreateButton>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    execute_webhook(http, webhook, build_text_message(mentions, content, buttons)).await
+}
+
+const MAX_SEND_ATTEMPTS: u32 = 3;
+const SEND_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Sends a message and waits for Discord to return it, so its ID can be kept to edit it later.
+/// Rate limits are already waited out by serenity's `Http`, so only Discord server errors are retried here.
+/// Other errors aren't, since the message may have been sent anyway.
+pub async fn send_tracked_message(
+    http: &Http,
+    webhook: &WebhookToken,
+    message: ExecuteWebhook,
+) -> Result<Option<MessageId>, Box<dyn std::error::Error>> {
+    let mut attempt = 1;
+    let message = match webhook.thread {
+        Some(thread) => message.in_thread(thread),
+        None => message
+    };
+
+    loop {
+        match message.clone().execute(http, (webhook.id, &webhook.token, true)).await {
+            Ok(sent) => return Ok(sent.map(|message| message.id)),
+            Err(serenity::Error::Http(err)) if attempt < MAX_SEND_ATTEMPTS
+            && err.status_code().is_some_and(|status| status.is_server_error()) => {
+                warn!("Discord returned an error sending a webhook message, retrying: {err}");
+                tokio::time::sleep(SEND_RETRY_DELAY).await;
+                attempt += 1;
+            },
+            Err(err) => return Err(err.into())
+        }
     }
+}
 
-    execute_webhook(http, &webhook, message).await
-}
\ No newline at end of file
+/// Replaces a message sent earlier with plain text, removing its embeds but keeping its buttons.
+pub async fn replace_webhook_message(
+    http: &Http,
+    webhook: &WebhookToken,
+    message_id: MessageId,
+    content: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut edit = EditWebhookMessage::new()
+        .content(content)
+        .embeds(Vec::new())
+        .allowed_mentions(CreateAllowedMentions::new());
+
+    if let Some(thread) = webhook.thread {
+        edit = edit.in_thread(thread);
+    }
+
+    edit.execute(http, (webhook.id, &webhook.token, message_id)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_tokens_are_parsed_from_urls() {
+        let token = parse_webhook_token("https://discord.com/api/webhooks/123456/abc-DEF_ghi").unwrap();
+        assert_eq!(token, WebhookToken { id: WebhookId::new(123456), token: "abc-DEF_ghi".to_string(), thread: None });
+
+        assert_eq!(parse_webhook_token("https://discordapp.com/api/webhooks/1/token/?wait=true").unwrap().token, "token");
+        assert_eq!(parse_webhook_token("https://discord.com/api/webhooks/1/token?thread_id=2").unwrap().token, "token");
+    }
+
+    #[test]
+    fn webhook_threads_are_kept() {
+        let token = parse_webhook_token("https://discord.com/api/webhooks/1/token?thread_id=2").unwrap();
+        assert_eq!(token.thread, Some(ChannelId::new(2)));
+
+        let token = parse_webhook_token("https://discord.com/api/webhooks/1/token?wait=true&thread_id=3").unwrap();
+        assert_eq!(token.thread, Some(ChannelId::new(3)));
+
+        assert_eq!(parse_webhook_token("https://discord.com/api/webhooks/1/token?thread_id=0").unwrap().thread, None);
+    }
+
+    #[test]
+    fn invalid_webhook_urls_are_rejected() {
+        assert_eq!(parse_webhook_token("https://discord.com/api/webhooks/123456"), None);
+        assert_eq!(parse_webhook_token("https://discord.com/api/webhooks/0/token"), None);
+        assert_eq!(parse_webhook_token("https://discord.com/api/webhooks/abc/token"), None);
+        assert_eq!(parse_webhook_token("https://discord.com/api/webhooks/123456/"), None);
+        assert_eq!(parse_webhook_token("https://example.com/hooks/123456/token"), None);
+    }
+
+    #[test]
+    fn compact_messages_start_with_the_timestamp() {
+        assert_eq!(build_compact_message("Testlandia arrived", 1700000000, Some("R")), "<t:1700000000:R> Testlandia arrived");
+        assert_eq!(build_compact_message("Testlandia arrived", 1700000000, None), "Testlandia arrived");
+    }
+}
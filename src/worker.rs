@@ -1,13 +1,19 @@
 use tokio::sync::mpsc;
 use std::{collections::HashSet, sync::Arc, time::Duration};
+use log::{error, info};
+use serenity::all::Http;
 
-use caramel::ns::api::Client;
+use caramel::ns::{api::Client, UserAgent};
+use caramel::types::akari::Event;
 
-use crate::{api, cache::NSCache};
+use crate::{api, cache::{NSCache, unix_time}, config::Config, events::{classify_sc_target, classify_votes, track_resolution_at_vote}, output, rmb::output_rmb_digest, utils::council_id};
 
 pub enum NSQuery {
     UpdateWA,
     UpdateTag(String),
+    UpdateFlag(String),
+    UpdateResidents(String),
+    UpdateDelegate(String),
 }
 
 pub fn spawn_ns_worker(
@@ -43,9 +49,159 @@ pub fn spawn_ns_worker(
                         break;
                     }
                 },
+                NSQuery::UpdateResidents(region) => {
+                    if let Ok(Some(residents)) = api::query_region_nations(&client, &region).await {
+                        info!("Queried {} residents of {region}", residents.len());
+                        cache.set_residents(&region, residents).await;
+                    }
+                },
+                NSQuery::UpdateDelegate(region) => {
+                    if let Ok(Some(delegate)) = api::query_region_delegate(&client, &region).await {
+                        if let Some(delegate) = &delegate
+                        && let Ok(Some(endorsements)) = api::query_nation_endorsements(&client, delegate).await {
+                            info!("Queried delegate of {region}: {delegate} with {} endorsements", endorsements.len());
+                            cache.set_endorsements(delegate, endorsements).await;
+                        }
+
+                        cache.set_delegate(&region, delegate).await;
+                    }
+                },
+                NSQuery::UpdateFlag(nation) => {
+                    // Flags are only cosmetic, so failed requests aren't retried
+                    if let Ok(Some(flag)) = api::query_nation_flag(&client, &nation).await {
+                        cache.flags.write().await.insert(&nation, flag);
+                    }
+
+                    cache.pending_flags.write().await.remove(&nation);
+                },
+            }
+        }
+    });
+
+    send
+}
+
+pub fn spawn_snapshot_worker(
+    cache: Arc<NSCache>,
+    path: String,
+    period: Duration,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await; // The first tick completes right away
+
+        loop {
+            interval.tick().await;
+
+            cache.save_snapshot(&path).await.unwrap_or_else(|err| {
+                error!("Failed to save cache snapshot to {path}: {err}");
+            });
+        }
+    });
+}
+
+const DIGEST_CHECK_INTERVAL: u64 = 30;
+
+pub fn spawn_digest_worker(
+    http: Arc<Http>,
+    cache: Arc<NSCache>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(DIGEST_CHECK_INTERVAL));
+
+        loop {
+            interval.tick().await;
+
+            for digest in cache.take_due_digests().await {
+                output_rmb_digest(&http, &digest).await.unwrap_or_else(|err| {
+                    error!("Failed to send RMB digest for {} to webhook: {err}", digest.region);
+                });
+            }
+        }
+    });
+}
+
+/// Polls the vote totals of resolutions at vote every `period`, sending tallies, lead changes and
+/// watched delegates' votes to the world config. Tracking ends when the resolution passes or fails.
+pub fn spawn_tally_worker(
+    client: Arc<Client>,
+    http: Arc<Http>,
+    config: Arc<Config>,
+    cache: Arc<NSCache>,
+    user_agent: UserAgent,
+    period: Duration,
+) {
+    let watched = config.watched_delegates();
+
+    tokio::spawn(async move {
+        // Resolutions already at vote never get an rsfloor, so their tallies start from the current votes
+        for chamber in ["General Assembly", "Security Council"] {
+            let Ok(Some(votes)) = api::query_wa_votes(&client, council_id(chamber)).await else { continue };
+            let time = unix_time();
+            track_resolution_at_vote(chamber, votes, time, &cache).await;
+        }
+
+        // Wait a full period before the first tally, so restarting doesn't send every tally again right away
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            let tallies: Vec<_> = cache.vote_tallies.read().await.values().cloned().collect();
+
+            for tally in tallies {
+                let (Some(chamber), Some(proposal)) = (tally.event.data.first(), tally.event.data.get(1)) else { continue };
+                let Ok(Some(votes)) = api::query_wa_votes(&client, council_id(chamber)).await else { continue };
+
+                // The resolution may have been decided since the last check
+                if &votes.name != proposal { continue; }
+
+                let time = unix_time();
+
+                for (data, event) in classify_votes(&tally, &votes, &watched, time) {
+                    if let Some(output_config) = config.get_world_event(data.name) {
+                        output::output_event(&http, &data, &output_config, &event, &cache, &user_agent).await.unwrap_or_else(|err| {
+                            error!("Failed to send event {event:?} to webhook: {err}");
+                        });
+                    }
+                }
+
+                if let Some(current) = cache.vote_tallies.write().await.get_mut(chamber) {
+                    current.lead = votes.lead().or(current.lead);
+                    current.votes = Some(votes);
+                }
+            }
+        }
+    });
+}
+
+/// Looks up the targets of Security Council proposals as they're submitted or reach the floor,
+/// sending `sc-target` happenings to the regions concerned.
+pub fn spawn_target_worker(
+    client: Arc<Client>,
+    http: Arc<Http>,
+    config: Arc<Config>,
+    cache: Arc<NSCache>,
+    user_agent: UserAgent,
+) -> mpsc::Sender<Event> {
+    let (send, mut recv) = mpsc::channel::<Event>(100);
+
+    tokio::spawn(async move {
+        while let Some(event) = recv.recv().await {
+            let proposal = if event.category == "rssubmit" { event.data.get(2) } else { event.data.get(1) };
+            let Some(proposal) = proposal else { continue };
+
+            let Ok(Some(target)) = api::query_sc_target(&client, proposal, event.category == "rsfloor").await else { continue };
+
+            for (target, data, event) in classify_sc_target(&event, &target, &config, &cache).await {
+                let Some(output_config) = config.get_target_event(&target, data.name) else { continue };
+
+                output::output_event(&http, &data, &output_config, &event, &cache, &user_agent).await.unwrap_or_else(|err| {
+                    error!("Failed to send event {event:?} to webhook: {err}");
+                });
             }
         }
     });
 
     send
-}
\ No newline at end of file
+}